@@ -0,0 +1,153 @@
+//! 倍率色収差の補正
+
+use ndarray::{Array2, Array3, ArrayView2, Axis};
+
+/// R/Bチャンネルを光学中心からの放射方向に拡大縮小してGチャンネルに合わせる
+///
+/// `rgb`はデモザイク後の`(height, width, 3)`の画像で、チャンネルはR, G, Bの順とする。
+/// `r_scale`、`b_scale`はGに対して各チャンネルが何倍に写っているかを表し、
+/// 出力の画素`p`には入力の`center + (p - center) * scale`の位置をバイリニア補間した値が入る。
+/// 画像の外を参照する場合は端の画素を使う。
+///
+/// `center`は光学中心を画素単位の`(row, col)`で指定する。
+///
+/// # Panics
+///
+/// `rgb`のチャンネル数が3でない場合
+pub fn correct_chromatic_aberration(
+    rgb: &mut Array3<u16>,
+    r_scale: f32,
+    b_scale: f32,
+    center: (f32, f32),
+) {
+    assert_eq!(rgb.shape()[2], 3, "rgb must have 3 channels");
+    for (ch, scale) in [(0, r_scale), (2, b_scale)] {
+        if scale == 1.0 {
+            continue;
+        }
+        let src = rgb.index_axis(Axis(2), ch).to_owned();
+        let dst = radial_resample(src.view(), scale, center);
+        rgb.index_axis_mut(Axis(2), ch).assign(&dst);
+    }
+}
+
+// 中心からの距離を`scale`倍した位置を参照して再サンプリングする
+fn radial_resample(src: ArrayView2<u16>, scale: f32, center: (f32, f32)) -> Array2<u16> {
+    let (h, w) = src.dim();
+    let (cy, cx) = center;
+    Array2::from_shape_fn((h, w), |(i, j)| {
+        let x = cx + (j as f32 - cx) * scale;
+        let y = cy + (i as f32 - cy) * scale;
+        bilinear(&src, x, y)
+    })
+}
+
+// 端でクランプするバイリニア補間
+#[inline]
+fn bilinear(src: &ArrayView2<u16>, x: f32, y: f32) -> u16 {
+    let (h, w) = src.dim();
+    let x = x.clamp(0.0, (w - 1) as f32);
+    let y = y.clamp(0.0, (h - 1) as f32);
+    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+    let (x1, y1) = ((x0 + 1).min(w - 1), (y0 + 1).min(h - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+    let top = src[[y0, x0]] as f32 * (1.0 - fx) + src[[y0, x1]] as f32 * fx;
+    let bottom = src[[y1, x0]] as f32 * (1.0 - fx) + src[[y1, x1]] as f32 * fx;
+    (top * (1.0 - fy) + bottom * fy).round() as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{s, Array2, Array3, ArrayView1, Axis};
+
+    use super::correct_chromatic_aberration;
+
+    // 縦エッジの横方向のプロファイル
+    fn edge(x: f32, pos: f32) -> u16 {
+        (((x - pos) / 4.0 + 0.5).clamp(0.0, 1.0) * 4000.0).round() as u16
+    }
+
+    // 値が半分を横切る位置をサブピクセルで求める
+    fn edge_position(row: ArrayView1<u16>) -> f32 {
+        let half = 2000.0;
+        for j in 1..row.len() {
+            let (a, b) = (row[j - 1] as f32, row[j] as f32);
+            if a < half && b >= half {
+                return (j - 1) as f32 + (half - a) / (b - a);
+            }
+        }
+        panic!("edge not found");
+    }
+
+    #[test]
+    fn test_correct_chromatic_aberration() {
+        let (h, w) = (64, 64);
+        // 縦横で異なる(row, col)の光学中心
+        let center = (40.0, 24.0);
+        let scale = 1.05;
+        let pos = 56.0;
+        // RだけがGに対して`scale`倍に写っている
+        let rgb = Array3::from_shape_fn((h, w, 3), |(_, j, ch)| {
+            let x = j as f32;
+            match ch {
+                0 => edge(center.1 + (x - center.1) / scale, pos),
+                _ => edge(x, pos),
+            }
+        });
+
+        let row = h / 2;
+        let offset = |rgb: &Array3<u16>| {
+            let r = edge_position(rgb.slice(s![row, .., 0]));
+            let g = edge_position(rgb.slice(s![row, .., 1]));
+            (r - g).abs()
+        };
+        let before = offset(&rgb);
+        assert!(before > 1.0, "before: {}", before);
+
+        let mut corrected = rgb.clone();
+        correct_chromatic_aberration(&mut corrected, scale, 1.0, center);
+        let after = offset(&corrected);
+        assert!(after < 0.1, "after: {}", after);
+        // G, Bは変更されない
+        assert_eq!(corrected.index_axis(Axis(2), 1), rgb.index_axis(Axis(2), 1));
+        assert_eq!(corrected.index_axis(Axis(2), 2), rgb.index_axis(Axis(2), 2));
+    }
+
+    #[test]
+    fn test_correct_chromatic_aberration_center_order() {
+        let (h, w) = (48, 64);
+        let center = (12.0, 44.0);
+        let scale = 1.04;
+        let field = |y: f32, x: f32| 2000.0 + 1500.0 * (x / 6.0).sin() * (y / 5.0).cos();
+        // Bだけが光学中心から`scale`倍に写っている
+        let rgb = Array3::from_shape_fn((h, w, 3), |(i, j, ch)| {
+            let (y, x) = (i as f32, j as f32);
+            let v = match ch {
+                2 => field(
+                    center.0 + (y - center.0) / scale,
+                    center.1 + (x - center.1) / scale,
+                ),
+                _ => field(y, x),
+            };
+            v.round() as u16
+        });
+
+        // 端のクランプの影響を受けない内側でBとGの差を比べる
+        let error = |rgb: &Array3<u16>| {
+            let g = rgb.slice(s![8..40, 8..56, 1]).mapv(|v| v as f64);
+            let b = rgb.slice(s![8..40, 8..56, 2]).mapv(|v| v as f64);
+            let diff: Array2<f64> = &b - &g;
+            diff.mapv(|d| d * d).mean().unwrap().sqrt()
+        };
+
+        let mut corrected = rgb.clone();
+        correct_chromatic_aberration(&mut corrected, 1.0, scale, center);
+        let mut swapped = rgb.clone();
+        correct_chromatic_aberration(&mut swapped, 1.0, scale, (center.1, center.0));
+
+        assert!(error(&rgb) > 100.0, "before: {}", error(&rgb));
+        assert!(error(&corrected) < 5.0, "corrected: {}", error(&corrected));
+        assert!(error(&swapped) > 100.0, "swapped: {}", error(&swapped));
+    }
+}
@@ -0,0 +1,95 @@
+//! ダーク/フラットフィールド補正
+
+use ndarray::{Array2, Zip};
+
+use crate::ImageStack;
+
+/// ダーク減算とフラット補正のマスターフレーム
+pub struct Calibration {
+    /// マスターダーク
+    pub dark: Array2<f64>,
+    /// ダーク減算済みのマスターフラット
+    pub flat: Array2<f64>,
+}
+
+impl Calibration {
+    /// ダークとフラットの画像スタックからマスターフレームを作成する
+    ///
+    /// フラットはダークを差し引いて0未満を0にクランプする
+    pub fn new(dark: &ImageStack, flat: &ImageStack) -> Self {
+        let dark = dark.mean();
+        let mut flat = flat.mean() - &dark;
+        flat.mapv_inplace(|x| x.max(0.0));
+        Calibration { dark, flat }
+    }
+
+    /// RAW画像を補正する
+    ///
+    /// 画素ごとに`(raw - dark) / flat * mean(flat)`を計算する。
+    /// ダーク減算後の値は0にクランプし、フラットが0の画素は0を返す。
+    pub fn apply(&self, raw: &Array2<u16>) -> Array2<f64> {
+        let flat_mean = self.flat.mean().unwrap_or(0.0);
+        Zip::from(raw)
+            .and(&self.dark)
+            .and(&self.flat)
+            .map_collect(|&r, &d, &f| {
+                if f > 0.0 {
+                    (r as f64 - d).max(0.0) / f * flat_mean
+                } else {
+                    0.0
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2};
+
+    use super::Calibration;
+    use crate::ImageStack;
+
+    #[test]
+    fn test_calibration_flatten_gradient() {
+        let (h, w) = (8, 16);
+        // 周辺減光を模した横方向の勾配
+        let gain = |j: usize| 0.5 + 0.5 * j as f64 / (w - 1) as f64;
+        let pedestal = 64.0;
+
+        let dark = Array2::from_elem((h, w), pedestal as u16);
+        let flat = Array2::from_shape_fn((h, w), |(_, j)| (pedestal + 1000.0 * gain(j)) as u16);
+        let raw = Array2::from_shape_fn((h, w), |(_, j)| (pedestal + 500.0 * gain(j)) as u16);
+
+        let mut darks = ImageStack::new(&dark.view());
+        darks.push(dark.view());
+        let mut flats = ImageStack::new(&flat.view());
+        flats.push(flat.view());
+
+        let calib = Calibration::new(&darks, &flats);
+        let out = calib.apply(&raw);
+        assert_eq!(out.dim(), (h, w));
+
+        // 入力の勾配が補正されてほぼ一定になる
+        assert!(raw.mapv(|x| x as f64).std(0.0) > 50.0);
+        assert!(out.std(0.0) < 1.0, "std: {}", out.std(0.0));
+        let expected = 500.0 * (0..w).map(gain).sum::<f64>() / w as f64;
+        assert!((out.mean().unwrap() - expected).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_calibration_clamp() {
+        let dark = Array2::from_elem((2, 2), 100_u16);
+        let flat = array![[100_u16, 200], [300, 400]];
+        let calib = Calibration::new(
+            &ImageStack::new(&dark.view()),
+            &ImageStack::new(&flat.view()),
+        );
+
+        let raw = array![[50_u16, 300], [100, 500]];
+        let out = calib.apply(&raw);
+        // ダーク以下は0、フラットが0の画素も0
+        assert_eq!(out[[0, 0]], 0.0);
+        assert_eq!(out[[1, 0]], 0.0);
+        assert!(out[[0, 1]] > 0.0);
+    }
+}
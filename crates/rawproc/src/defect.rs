@@ -0,0 +1,153 @@
+//! 欠陥画素(ホットピクセル/デッドピクセル)の検出と補正
+
+use ndarray::Array2;
+
+use crate::BayerPattern;
+
+/// 隣接する欠陥画素が続く場合に探索を広げる最大の半径
+const MAX_SEARCH_RADIUS: usize = 8;
+
+/// 同色の周囲8画素の中央値から大きく外れた画素を欠陥として検出する
+///
+/// ベイヤー配列ではどの色でも2画素離れた位置が同色になるため、`±2`の位置の画素と比較する。
+/// 画像の端では存在する画素のみを使う。
+pub fn detect_defects(img: &Array2<u16>, threshold: u16) -> Array2<bool> {
+    let (h, w) = img.dim();
+    Array2::from_shape_fn((h, w), |(i, j)| {
+        let mut neighbors = vec![];
+        for di in [-2, 0, 2] {
+            for dj in [-2, 0, 2] {
+                if di == 0 && dj == 0 {
+                    continue;
+                }
+                if let Some(p) = offset(i, j, di, dj, h, w) {
+                    neighbors.push(img[p] as f64);
+                }
+            }
+        }
+        if neighbors.is_empty() {
+            return false;
+        }
+        let m = crate::median(&mut neighbors);
+        (img[[i, j]] as f64 - m).abs() > threshold as f64
+    })
+}
+
+/// 欠陥画素を同色の周囲の画素の平均で置き換える
+///
+/// 近い順に半径を広げながら同色かつ欠陥でない画素を探し、見つかった半径の画素の平均を使う。
+/// 例えばRの画素は2画素離れたRの8画素、Gの画素は斜めに隣接する4画素から補間される。
+/// 欠陥が固まっている場合も、正常な画素が見つかるまで探索範囲を広げる。
+///
+/// # Panics
+///
+/// `defects`の大きさが`img`と異なる場合
+pub fn correct_defects(img: &mut Array2<u16>, defects: &Array2<bool>, pattern: BayerPattern) {
+    assert_eq!(img.dim(), defects.dim(), "defect map size mismatch");
+    let (h, w) = img.dim();
+    let ptn = pattern.ptn();
+    let color = |i: usize, j: usize| ptn[[i % 2, j % 2]];
+
+    for ((i, j), _) in defects.indexed_iter().filter(|(_, &d)| d) {
+        let c = color(i, j);
+        for r in 1..=MAX_SEARCH_RADIUS {
+            let (mut sum, mut count) = (0_u32, 0_u32);
+            for ii in i.saturating_sub(r)..(i + r + 1).min(h) {
+                for jj in j.saturating_sub(r)..(j + r + 1).min(w) {
+                    if color(ii, jj) == c && !defects[[ii, jj]] {
+                        sum += img[[ii, jj]] as u32;
+                        count += 1;
+                    }
+                }
+            }
+            if let Some(avg) = (sum + count / 2).checked_div(count) {
+                img[[i, j]] = avg as u16;
+                break;
+            }
+        }
+    }
+}
+
+// 画像の範囲内であればオフセットを加えた座標を返す
+#[inline]
+fn offset(i: usize, j: usize, di: isize, dj: isize, h: usize, w: usize) -> Option<[usize; 2]> {
+    let i = i.checked_add_signed(di).filter(|&i| i < h)?;
+    let j = j.checked_add_signed(dj).filter(|&j| j < w)?;
+    Some([i, j])
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use super::{correct_defects, detect_defects};
+    use crate::BayerPattern;
+
+    // 色ごとに異なるなだらかな信号
+    fn bayer_signal(h: usize, w: usize) -> Array2<u16> {
+        Array2::from_shape_fn((h, w), |(i, j)| {
+            let base = [[400, 800], [800, 300]][i % 2][j % 2];
+            base + (i + j) as u16
+        })
+    }
+
+    #[test]
+    fn test_correct_hot_pixel() {
+        let mut img = bayer_signal(10, 10);
+        // RGGBの(4, 4)はR
+        let (y, x) = (4, 4);
+        img[[y, x]] = 4095;
+
+        let defects = detect_defects(&img, 200);
+        assert_eq!(defects.iter().filter(|&&d| d).count(), 1);
+        assert!(defects[[y, x]]);
+
+        let mut expected = 0_u32;
+        for (i, j) in [
+            (2, 2),
+            (2, 4),
+            (2, 6),
+            (4, 2),
+            (4, 6),
+            (6, 2),
+            (6, 4),
+            (6, 6),
+        ] {
+            expected += img[[i, j]] as u32;
+        }
+        let expected = ((expected + 4) / 8) as u16;
+
+        correct_defects(&mut img, &defects, BayerPattern::RGGB);
+        assert_eq!(img[[y, x]], expected);
+        assert_eq!(img, bayer_signal(10, 10));
+    }
+
+    #[test]
+    fn test_correct_green_uses_diagonal() {
+        let mut img = bayer_signal(6, 6);
+        // RGGBの(2, 3)はG
+        img[[2, 3]] = 0;
+        let mut defects = Array2::from_elem(img.dim(), false);
+        defects[[2, 3]] = true;
+
+        correct_defects(&mut img, &defects, BayerPattern::RGGB);
+        let expected = (img[[1, 2]] + img[[1, 4]] + img[[3, 2]] + img[[3, 4]]) / 4;
+        assert_eq!(img[[2, 3]], expected);
+    }
+
+    #[test]
+    fn test_correct_defect_cluster() {
+        let mut img = Array2::from_elem((12, 12), 100_u16);
+        let mut defects = Array2::from_elem(img.dim(), false);
+        // 中央の6x6を欠陥とする
+        for i in 3..9 {
+            for j in 3..9 {
+                img[[i, j]] = 4095;
+                defects[[i, j]] = true;
+            }
+        }
+
+        correct_defects(&mut img, &defects, BayerPattern::BGGR);
+        assert!(img.iter().all(|&v| v == 100));
+    }
+}
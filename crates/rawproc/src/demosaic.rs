@@ -0,0 +1,271 @@
+//! ベイヤー配列からRGB画像への変換
+
+use ndarray::{Array2, Array3};
+
+use crate::BayerPattern;
+
+/// ハイライト復元で周囲の色の比率を探す半径
+const RECOVERY_RADIUS: usize = 2;
+
+/// デモザイクの設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DemosaicOptions {
+    /// 飽和したチャンネルを周囲の飽和していない画素の色の比率から復元する
+    pub recover_highlights: bool,
+    /// この値以上の画素を飽和しているとみなす
+    pub white_level: u16,
+}
+
+impl Default for DemosaicOptions {
+    fn default() -> Self {
+        DemosaicOptions {
+            recover_highlights: false,
+            white_level: u16::MAX,
+        }
+    }
+}
+
+/// バイリニア補間でデモザイクする
+///
+/// 各画素について、自身の色はそのまま使い、足りない2色は周囲3x3にある同じ色の画素の平均で補間する。
+/// 出力は`(height, width, 3)`でチャンネルはR, G, Bの順になる。
+/// 画像の端では範囲内にある画素のみで平均を取る。
+pub fn demosaic_bilinear(img: &Array2<u16>, pattern: BayerPattern) -> Array3<u16> {
+    demosaic_bilinear_with(img, pattern, &DemosaicOptions::default())
+}
+
+/// 設定を指定してバイリニア補間でデモザイクする
+///
+/// `recover_highlights`が有効な場合、補間に`white_level`以上の画素を含むチャンネルを飽和とみなし、
+/// 周囲の飽和していない画素のチャンネル間の比率から値を推定する。
+/// 推定値は色相を保ったまま`white_level`に収まるよう縮め、飽和したチャンネルが多いほど白に近づける。
+/// 一部のチャンネルだけが飽和して空などがマゼンタや緑に色付くのを抑える。
+pub fn demosaic_bilinear_with(
+    img: &Array2<u16>,
+    pattern: BayerPattern,
+    options: &DemosaicOptions,
+) -> Array3<u16> {
+    let (h, w) = img.dim();
+    let ptn = pattern.ptn();
+    let white = options.white_level;
+    let mut dst = Array3::<u16>::zeros((h, w, 3));
+    let mut clipped = Array3::from_elem((h, w, 3), false);
+
+    for ((i, j), &v) in img.indexed_iter() {
+        let own = ptn[[i % 2, j % 2]];
+        for (ch, bit) in [1_u8, 2, 4].into_iter().enumerate() {
+            let (value, clip) = if own == bit {
+                (v, v >= white)
+            } else {
+                neighbor_mean(img, &ptn, i, j, bit, white)
+            };
+            dst[[i, j, ch]] = value;
+            clipped[[i, j, ch]] = clip;
+        }
+    }
+    if options.recover_highlights {
+        recover_highlights(&mut dst, &clipped, white);
+    }
+    dst
+}
+
+// 周囲3x3にある指定した色の画素の平均と、その中に飽和した画素が含まれるか
+#[inline]
+fn neighbor_mean(
+    img: &Array2<u16>,
+    ptn: &Array2<u8>,
+    i: usize,
+    j: usize,
+    bit: u8,
+    white: u16,
+) -> (u16, bool) {
+    let (h, w) = img.dim();
+    let (mut sum, mut count, mut clip) = (0_u32, 0_u32, false);
+    for ii in i.saturating_sub(1)..(i + 2).min(h) {
+        for jj in j.saturating_sub(1)..(j + 2).min(w) {
+            if ptn[[ii % 2, jj % 2]] == bit {
+                let v = img[[ii, jj]];
+                sum += v as u32;
+                count += 1;
+                clip |= v >= white;
+            }
+        }
+    }
+    let mean = (sum + count / 2).checked_div(count).unwrap_or(0) as u16;
+    (mean, clip)
+}
+
+// 飽和したチャンネルを周囲の色の比率から推定し、白に向けて彩度を落とす
+fn recover_highlights(rgb: &mut Array3<u16>, clipped: &Array3<bool>, white: u16) {
+    let (h, w, _) = rgb.dim();
+    let src = rgb.clone();
+    let white_f = white as f64;
+
+    for i in 0..h {
+        for j in 0..w {
+            let clip = [clipped[[i, j, 0]], clipped[[i, j, 1]], clipped[[i, j, 2]]];
+            let n_clipped = clip.iter().filter(|&&c| c).count();
+            if n_clipped == 0 {
+                continue;
+            }
+            // 比率の基準には飽和していないGを優先して使う
+            let Some(reference) = [1, 0, 2].into_iter().find(|&c| !clip[c]) else {
+                for ch in 0..3 {
+                    rgb[[i, j, ch]] = white;
+                }
+                continue;
+            };
+
+            let px = [0, 1, 2].map(|ch| src[[i, j, ch]] as f64);
+            let mut est = px;
+            for ch in (0..3).filter(|&c| clip[c]) {
+                let (mut sum, mut count) = (0.0, 0);
+                for ii in i.saturating_sub(RECOVERY_RADIUS)..(i + RECOVERY_RADIUS + 1).min(h) {
+                    for jj in j.saturating_sub(RECOVERY_RADIUS)..(j + RECOVERY_RADIUS + 1).min(w) {
+                        let unclipped = (0..3).all(|c| !clipped[[ii, jj, c]]);
+                        let base = src[[ii, jj, reference]] as f64;
+                        if unclipped && base > 0.0 {
+                            sum += src[[ii, jj, ch]] as f64 / base;
+                            count += 1;
+                        }
+                    }
+                }
+                // 飽和している以上、真の値は`white`以上
+                est[ch] = if count > 0 {
+                    (px[reference] * sum / count as f64).max(white_f)
+                } else {
+                    white_f
+                };
+            }
+
+            let max = est.iter().cloned().fold(0.0, f64::max);
+            let scale = if max > white_f { white_f / max } else { 1.0 };
+            let weight = n_clipped as f64 / 3.0;
+            for (ch, v) in est.into_iter().enumerate() {
+                let v = v * scale;
+                rgb[[i, j, ch]] = (v + (white_f - v) * weight).round().min(white_f) as u16;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{s, Array2, Array3, Axis};
+
+    use super::{demosaic_bilinear, demosaic_bilinear_with, DemosaicOptions};
+    use crate::{image_to_ndarray, BayerPattern};
+
+    const PATTERNS: [BayerPattern; 4] = [
+        BayerPattern::RGGB,
+        BayerPattern::BGGR,
+        BayerPattern::GBRG,
+        BayerPattern::GRBG,
+    ];
+
+    #[test]
+    fn test_demosaic_32x32() {
+        let img = image::load_from_memory(include_bytes!("../../../testdata/32x32.png"))
+            .unwrap()
+            .into_luma16();
+        let img = image_to_ndarray(&img).unwrap();
+        for ptn in PATTERNS {
+            let rgb = demosaic_bilinear(&img, ptn);
+            assert_eq!(rgb.shape(), [32, 32, 3]);
+        }
+    }
+
+    #[test]
+    fn test_demosaic_flat_field() {
+        let img = Array2::from_elem((32, 32), 1000_u16);
+        for ptn in PATTERNS {
+            let rgb = demosaic_bilinear(&img, ptn);
+            assert!(rgb.iter().all(|&v| v == 1000));
+        }
+    }
+
+    #[test]
+    fn test_demosaic_channel_levels() {
+        // 色ごとに異なる一様な値を持つモザイク
+        let (r, g, b) = (100_u16, 2000, 3000);
+        for ptn in PATTERNS {
+            let p = ptn.ptn();
+            let img = Array2::from_shape_fn((9, 7), |(i, j)| match p[[i % 2, j % 2]] {
+                1 => r,
+                2 => g,
+                _ => b,
+            });
+            let rgb = demosaic_bilinear(&img, ptn);
+            for (ch, level) in [r, g, b].into_iter().enumerate() {
+                let plane = rgb.slice(s![.., .., ch]);
+                assert!(plane.iter().all(|&v| v == level), "{:?} ch{}", ptn, ch);
+            }
+        }
+    }
+
+    // 最大のチャンネルに対する最小のチャンネルの差の比率。0なら無彩色
+    fn spread(rgb: &[u16]) -> f64 {
+        let max = *rgb.iter().max().unwrap() as f64;
+        let min = *rgb.iter().min().unwrap() as f64;
+        (max - min) / max
+    }
+
+    #[test]
+    fn test_demosaic_recover_highlights() {
+        let white = 4095_u16;
+        let ptn = BayerPattern::RGGB;
+        let p = ptn.ptn();
+        // 無彩色に近い被写体で、右半分の明るい領域だけRが飽和している
+        let gain = |bit: u8| match bit {
+            1 => 1.3,
+            2 => 1.0,
+            _ => 1.15,
+        };
+        let img = Array2::from_shape_fn((16, 32), |(i, j)| {
+            let level = if j < 16 { 1200.0 } else { 3400.0 };
+            ((level * gain(p[[i % 2, j % 2]])) as u16).min(white)
+        });
+
+        let naive = demosaic_bilinear(&img, ptn);
+        let options = DemosaicOptions {
+            recover_highlights: true,
+            white_level: white,
+        };
+        let recovered = demosaic_bilinear_with(&img, ptn, &options);
+
+        // 飽和領域の内側の平均
+        let mean_spread = |rgb: &Array3<u16>| {
+            let region = rgb.slice(s![2..14, 17..30, ..]);
+            let spreads: Vec<f64> = region
+                .lanes(Axis(2))
+                .into_iter()
+                .map(|px| spread(&px.to_vec()))
+                .collect();
+            spreads.iter().sum::<f64>() / spreads.len() as f64
+        };
+        let (naive_spread, recovered_spread) = (mean_spread(&naive), mean_spread(&recovered));
+        assert!(
+            recovered_spread < naive_spread,
+            "naive: {}, recovered: {}",
+            naive_spread,
+            recovered_spread
+        );
+        assert!(recovered.iter().all(|&v| v <= white));
+        // 飽和していない領域は変わらない
+        assert_eq!(
+            recovered.slice(s![.., ..14, ..]),
+            naive.slice(s![.., ..14, ..])
+        );
+    }
+
+    #[test]
+    fn test_demosaic_options_default() {
+        let img = Array2::from_shape_fn((9, 7), |(i, j)| (i * 300 + j * 40) as u16);
+        let options = DemosaicOptions::default();
+        assert!(!options.recover_highlights);
+        assert_eq!(
+            demosaic_bilinear_with(&img, BayerPattern::GBRG, &options),
+            demosaic_bilinear(&img, BayerPattern::GBRG)
+        );
+    }
+}
@@ -1,3 +1,11 @@
+pub mod aberration;
+pub mod calibration;
+pub mod defect;
+pub mod demosaic;
+pub mod mipi;
+pub mod noise;
+pub mod tile;
+
 use ndarray::{array, Array1, Array2, Array3, ArrayView1, ArrayView2, Axis, ShapeError};
 
 /// ベイヤーパターン
@@ -80,6 +88,76 @@ impl ColorMask {
     }
 }
 
+/// RAWデータの有効ビット数
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BitDepth {
+    Raw10,
+    Raw12,
+    Raw14,
+    Raw16,
+}
+
+impl BitDepth {
+    /// 有効ビット数
+    #[inline]
+    pub const fn bits(&self) -> u32 {
+        match self {
+            BitDepth::Raw10 => 10,
+            BitDepth::Raw12 => 12,
+            BitDepth::Raw14 => 14,
+            BitDepth::Raw16 => 16,
+        }
+    }
+
+    /// 右詰めのデータが取りうる最大値
+    #[inline]
+    pub const fn max_value(&self) -> u16 {
+        ((1_u32 << self.bits()) - 1) as u16
+    }
+}
+
+impl From<mipi::MipiRawBits> for BitDepth {
+    fn from(bits: mipi::MipiRawBits) -> Self {
+        match bits {
+            mipi::MipiRawBits::Raw10 => BitDepth::Raw10,
+            mipi::MipiRawBits::Raw12 => BitDepth::Raw12,
+        }
+    }
+}
+
+/// ベイヤー配列とビット深度を持つRAW画像
+///
+/// `data`は右詰めされている前提とする
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawImage {
+    pub data: Array2<u16>,
+    pub pattern: BayerPattern,
+    pub depth: BitDepth,
+}
+
+impl RawImage {
+    pub fn new(data: Array2<u16>, pattern: BayerPattern, depth: BitDepth) -> Self {
+        RawImage {
+            data,
+            pattern,
+            depth,
+        }
+    }
+
+    /// ビット深度の最大値で割って[0, 1]に正規化する
+    ///
+    /// 最大値を超える値は1.0にクランプする
+    pub fn normalize(&self) -> Array2<f32> {
+        let max = self.depth.max_value() as f32;
+        self.data.mapv(|x| (x as f32 / max).min(1.0))
+    }
+
+    /// 対象の色を抽出して1次元配列に変換する
+    pub fn mask_vec(&self, ch: ColorChannel) -> Array1<u16> {
+        self.pattern.mask(ch).mask_vec(&self.data)
+    }
+}
+
 /// 画像をndarrayに変換する
 pub fn image_to_ndarray(
     img: &image::ImageBuffer<image::Luma<u16>, Vec<u16>>,
@@ -89,6 +167,22 @@ pub fn image_to_ndarray(
     Ok(x.into_owned())
 }
 
+/// 中央値を求める
+///
+/// 要素数が偶数の場合は中央の2つの平均を返す。並べ替えのため入力は破壊される
+pub(crate) fn median(buf: &mut [f64]) -> f64 {
+    if buf.is_empty() {
+        return f64::NAN;
+    }
+    buf.sort_unstable_by(f64::total_cmp);
+    let n = buf.len();
+    if n % 2 == 1 {
+        buf[n / 2]
+    } else {
+        (buf[n / 2 - 1] + buf[n / 2]) / 2.0
+    }
+}
+
 /// 計算用に画像スタックを保持する構造体
 pub struct ImageStack {
     stack: Array3<f64>,
@@ -138,14 +232,50 @@ impl ImageStack {
     pub fn std(&self) -> Array2<f64> {
         self.stack.std_axis(Axis(0), 1.0)
     }
+
+    /// スタックの各画素の中央値を取得する
+    ///
+    /// 画素ごとに奥行き方向の値を並べ替えるため、スタックの枚数を`n`として1画素あたりO(n log n)かかる。
+    /// 一部のフレームにだけ現れる宇宙線などの外れ値の影響を受けない。
+    pub fn median(&self) -> Array2<f64> {
+        self.stack
+            .map_axis(Axis(0), |depth| median(&mut depth.to_vec()))
+    }
+
+    /// スタックの各画素の最大値を取得する
+    pub fn max(&self) -> Array2<f64> {
+        self.stack.map_axis(Axis(0), |depth| {
+            depth.fold(f64::NEG_INFINITY, |a, &b| a.max(b))
+        })
+    }
+
+    /// 全ての画像から黒レベルを引く。0未満は0にクランプする
+    pub fn subtract_black_level(&mut self, level: f64) {
+        self.stack.mapv_inplace(|x| (x - level).max(0.0));
+    }
+
+    /// ベイヤー配列のRとBの画素にゲインを掛けてホワイトバランスを合わせる
+    pub fn apply_white_balance(&mut self, pattern: BayerPattern, r_gain: f64, b_gain: f64) {
+        let ptn = pattern.ptn();
+        for ((_, i, j), x) in self.stack.indexed_iter_mut() {
+            match ptn[[i % 2, j % 2]] {
+                1 => *x *= r_gain,
+                4 => *x *= b_gain,
+                _ => {}
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use image::{ImageBuffer, Luma};
-    use ndarray::{array, Array3, Axis};
+    use ndarray::{array, Array2, Array3, Axis};
 
-    use crate::{image_to_ndarray, BayerPattern, ColorChannel, ImageStack};
+    use crate::{
+        image_to_ndarray, mipi::MipiRawBits, BayerPattern, BitDepth, ColorChannel, ImageStack,
+        RawImage,
+    };
 
     const TESTIMAGE_32X32: &[u8] = include_bytes!("../../../testdata/32x32.png");
 
@@ -194,6 +324,82 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_median_max_spike() {
+        let base = Array2::from_shape_fn((4, 4), |(i, j)| 100 + (i * 4 + j) as u16);
+        let mut stack = ImageStack::new(&base.view());
+        for _ in 0..3 {
+            stack.push(base.view());
+        }
+        // 1枚だけ宇宙線のような外れ値を持つ
+        let mut spiked = base.clone();
+        spiked[[2, 1]] = 4095;
+        stack.push(spiked.view());
+
+        let base = base.mapv(|x| x as f64);
+        assert_eq!(stack.median(), base);
+        assert!(stack.mean()[[2, 1]] > base[[2, 1]]);
+
+        let max = stack.max();
+        assert_eq!(max[[2, 1]], 4095.0);
+        let mut expected = base.clone();
+        expected[[2, 1]] = 4095.0;
+        assert_eq!(max, expected);
+    }
+
+    #[test]
+    fn test_black_level_white_balance() {
+        let black = 64.0;
+        let img = Array2::from_shape_fn((8, 8), |(i, j)| 100 + (i * 8 + j) as u16 * 10);
+        let mut stack = ImageStack::new(&img.view());
+        stack.push(img.view());
+
+        let ptn = BayerPattern::GRBG;
+        let channel_means = |stack: &ImageStack| {
+            let mean = stack.mean();
+            [ColorChannel::R, ColorChannel::G, ColorChannel::B]
+                .map(|ch| ptn.mask(ch).mask_vec(&mean).mean().unwrap())
+        };
+
+        let before = channel_means(&stack);
+        stack.subtract_black_level(black);
+        let after = channel_means(&stack);
+        for (b, a) in before.iter().zip(after.iter()) {
+            assert!((b - a - black).abs() < 1e-9);
+        }
+
+        let (r_gain, b_gain) = (1.8, 1.4);
+        stack.apply_white_balance(ptn, r_gain, b_gain);
+        let balanced = channel_means(&stack);
+        assert!((balanced[0] - after[0] * r_gain).abs() < 1e-9);
+        assert!((balanced[1] - after[1]).abs() < 1e-9);
+        assert!((balanced[2] - after[2] * b_gain).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_black_level_clamp() {
+        let img = array![[10_u16, 100], [50, 200]];
+        let mut stack = ImageStack::new(&img.view());
+        stack.subtract_black_level(64.0);
+        assert_eq!(stack.mean(), array![[0.0, 36.0], [0.0, 136.0]]);
+    }
+
+    #[test]
+    fn test_raw_image_normalize() {
+        let data = array![[4095_u16, 2048], [0, 1024]];
+        let raw = RawImage::new(data, BayerPattern::RGGB, BitDepth::Raw12);
+        let norm = raw.normalize();
+        assert_eq!(norm[[0, 0]], 1.0);
+        assert!((norm[[0, 1]] - 0.5).abs() < 1e-3);
+        assert_eq!(norm[[1, 0]], 0.0);
+        assert_eq!(raw.mask_vec(ColorChannel::R), array![4095]);
+
+        assert_eq!(BitDepth::Raw10.max_value(), 1023);
+        assert_eq!(BitDepth::Raw14.max_value(), 16383);
+        assert_eq!(BitDepth::Raw16.max_value(), u16::MAX);
+        assert_eq!(BitDepth::from(MipiRawBits::Raw12), BitDepth::Raw12);
+    }
+
     #[test]
     fn test_bayer_mask() {
         let arr: Array3<u16> = array![[[1, 2, 3, 4], [5, 6, 7, 8]], [[3, 4, 5, 6], [7, 8, 9, 0]]];
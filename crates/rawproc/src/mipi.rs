@@ -0,0 +1,160 @@
+//! MIPI CSI-2のパックされたRAWデータの展開
+
+use ndarray::{Array2, ErrorKind, ShapeError};
+
+/// MIPI CSI-2のRAWパッキング形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MipiRawBits {
+    /// 4画素を5byteに詰めたRAW10
+    Raw10,
+    /// 2画素を3byteに詰めたRAW12
+    Raw12,
+}
+
+impl MipiRawBits {
+    /// 1グループあたりの画素数
+    #[inline]
+    pub const fn pixels_per_group(&self) -> usize {
+        match self {
+            MipiRawBits::Raw10 => 4,
+            MipiRawBits::Raw12 => 2,
+        }
+    }
+
+    /// 1グループあたりのバイト数
+    #[inline]
+    pub const fn bytes_per_group(&self) -> usize {
+        match self {
+            MipiRawBits::Raw10 => 5,
+            MipiRawBits::Raw12 => 3,
+        }
+    }
+
+    /// 右詰めのデータを16bit幅で左詰めにするためのビットシフト数
+    #[inline]
+    pub const fn bitshift(&self) -> u32 {
+        match self {
+            MipiRawBits::Raw10 => 6,
+            MipiRawBits::Raw12 => 4,
+        }
+    }
+}
+
+/// 展開後の16bit画素でのビットの詰め方
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Justify {
+    /// 上位ビットに詰める。Jetsonのキャプチャと同じ配置
+    Left,
+    /// 下位ビットに詰める
+    #[default]
+    Right,
+}
+
+/// パックされたRAWデータを右詰めの16bit画素に展開する
+///
+/// 行間のパディングはないものとし、`width`は1グループの画素数の倍数である必要がある。
+/// バイト列の長さが画像サイズと一致しない場合はエラーを返す。
+pub fn unpack_mipi_raw(
+    bytes: &[u8],
+    width: usize,
+    height: usize,
+    bits: MipiRawBits,
+) -> Result<Array2<u16>, ShapeError> {
+    unpack_mipi_raw_justified(bytes, width, height, bits, Justify::Right)
+}
+
+/// パックされたRAWデータを`justify`で指定した詰め方の16bit画素に展開する
+///
+/// 左詰めの場合、下位の空きビットは0になる
+pub fn unpack_mipi_raw_justified(
+    bytes: &[u8],
+    width: usize,
+    height: usize,
+    bits: MipiRawBits,
+    justify: Justify,
+) -> Result<Array2<u16>, ShapeError> {
+    let ppg = bits.pixels_per_group();
+    let bpg = bits.bytes_per_group();
+    if width % ppg != 0 || bytes.len() != width / ppg * bpg * height {
+        return Err(ShapeError::from_kind(ErrorKind::IncompatibleShape));
+    }
+
+    let mut dst = Vec::with_capacity(width * height);
+    for g in bytes.chunks_exact(bpg) {
+        match bits {
+            MipiRawBits::Raw10 => {
+                // 先頭4byteが上位8bit、5byte目に各画素の下位2bitが並ぶ
+                let low = g[4] as u16;
+                for (i, &high) in g[..4].iter().enumerate() {
+                    dst.push(((high as u16) << 2) | ((low >> (i * 2)) & 0x03));
+                }
+            }
+            MipiRawBits::Raw12 => {
+                // 先頭2byteが上位8bit、3byte目に各画素の下位4bitが並ぶ
+                let low = g[2] as u16;
+                dst.push(((g[0] as u16) << 4) | (low & 0x0f));
+                dst.push(((g[1] as u16) << 4) | (low >> 4));
+            }
+        }
+    }
+    if justify == Justify::Left {
+        let shift = bits.bitshift();
+        dst.iter_mut().for_each(|x| *x <<= shift);
+    }
+    Array2::from_shape_vec((height, width), dst)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::array;
+
+    use super::{unpack_mipi_raw, unpack_mipi_raw_justified, Justify, MipiRawBits};
+
+    #[test]
+    fn test_unpack_raw10() {
+        // 0x3ff, 0x000, 0x2aa, 0x155 / 0x001, 0x002, 0x100, 0x203
+        let bytes = [
+            [0xff, 0x00, 0xaa, 0x55, 0b01_10_00_11],
+            [0x00, 0x00, 0x40, 0x80, 0b11_00_10_01],
+        ]
+        .concat();
+        let img = unpack_mipi_raw(&bytes, 4, 2, MipiRawBits::Raw10).unwrap();
+        assert_eq!(
+            img,
+            array![[0x3ff, 0x000, 0x2aa, 0x155], [0x001, 0x002, 0x100, 0x203]]
+        );
+    }
+
+    #[test]
+    fn test_unpack_raw12() {
+        // 0xfff, 0x000 / 0xabc, 0x123
+        let bytes = [0xff, 0x00, 0x0f, 0xab, 0x12, 0x3c];
+        let img = unpack_mipi_raw(&bytes, 2, 2, MipiRawBits::Raw12).unwrap();
+        assert_eq!(img, array![[0xfff, 0x000], [0xabc, 0x123]]);
+    }
+
+    #[test]
+    fn test_unpack_left_justified() {
+        let raw10 = [0xff, 0x00, 0xaa, 0x55, 0b01_10_00_11];
+        let img = unpack_mipi_raw_justified(&raw10, 4, 1, MipiRawBits::Raw10, Justify::Left);
+        assert_eq!(img.unwrap(), array![[0xffc0, 0x0000, 0xaa80, 0x5540]]);
+
+        let raw12 = [0xab, 0x12, 0x3c];
+        let img = unpack_mipi_raw_justified(&raw12, 2, 1, MipiRawBits::Raw12, Justify::Left);
+        assert_eq!(img.unwrap(), array![[0xabc0, 0x1230]]);
+
+        // 右詰めは`unpack_mipi_raw`と同じ
+        let img = unpack_mipi_raw_justified(&raw12, 2, 1, MipiRawBits::Raw12, Justify::Right);
+        assert_eq!(
+            img.unwrap(),
+            unpack_mipi_raw(&raw12, 2, 1, MipiRawBits::Raw12).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_unpack_invalid_size() {
+        assert!(unpack_mipi_raw(&[0; 5], 3, 1, MipiRawBits::Raw10).is_err());
+        assert!(unpack_mipi_raw(&[0; 4], 4, 1, MipiRawBits::Raw10).is_err());
+        assert!(unpack_mipi_raw(&[0; 6], 2, 1, MipiRawBits::Raw12).is_err());
+    }
+}
@@ -0,0 +1,90 @@
+//! センサーノイズの除去
+
+use std::ops::Range;
+
+use ndarray::{s, Array2, Axis};
+
+use crate::median;
+
+/// 行ごとのオフセット(横縞ノイズ)を除去する
+///
+/// `optical_black_cols`が指定されていれば、行ごとにその列の平均値を黒レベルとして行全体から引く。
+/// 空の範囲を渡した場合は行ごとの中央値を使い、全行の中央値との差分だけを引くことで
+/// 画像全体の信号レベルは維持する。
+///
+/// # Panics
+///
+/// `optical_black_cols`が画像の幅を超える場合
+pub fn remove_row_noise(img: &mut Array2<f64>, optical_black_cols: Range<usize>) {
+    if img.is_empty() {
+        return;
+    }
+    let offsets: Vec<f64> = if optical_black_cols.is_empty() {
+        let row_medians: Vec<f64> = img
+            .axis_iter(Axis(0))
+            .map(|row| median(&mut row.to_vec()))
+            .collect();
+        let level = median(&mut row_medians.clone());
+        row_medians.iter().map(|m| m - level).collect()
+    } else {
+        img.slice(s![.., optical_black_cols])
+            .mean_axis(Axis(1))
+            .unwrap()
+            .to_vec()
+    };
+    for (mut row, offset) in img.axis_iter_mut(Axis(0)).zip(offsets) {
+        row -= offset;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{s, Array2};
+
+    use super::remove_row_noise;
+
+    // 横方向のグラデーションを持つ信号
+    fn signal(h: usize, w: usize) -> Array2<f64> {
+        Array2::from_shape_fn((h, w), |(_, j)| 100.0 + j as f64 * 10.0)
+    }
+
+    // 行ごとに異なるオフセット
+    fn banding(i: usize) -> f64 {
+        [3.0, -7.0, 12.0, 0.0, 5.5, -2.0][i % 6]
+    }
+
+    #[test]
+    fn test_remove_row_noise_optical_black() {
+        let (h, w, ob) = (12, 20, 4);
+        let mut expected = signal(h, w);
+        expected.slice_mut(s![.., ..ob]).fill(0.0);
+
+        let black = 64.0;
+        let mut img = expected.clone();
+        for (i, mut row) in img.rows_mut().into_iter().enumerate() {
+            row += black + banding(i);
+        }
+
+        remove_row_noise(&mut img, 0..ob);
+        for (a, b) in img.iter().zip(expected.iter()) {
+            assert!((a - b).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_remove_row_noise_median() {
+        let (h, w) = (12, 21);
+        let expected = signal(h, w);
+        let mut img = expected.clone();
+        for (i, mut row) in img.rows_mut().into_iter().enumerate() {
+            row += banding(i);
+        }
+
+        remove_row_noise(&mut img, 0..0);
+        // 行間の差が無くなり、信号の形は一定のオフセットを除いて保たれる
+        let diff = &img - &expected;
+        let bias = diff[[0, 0]];
+        assert!(diff.iter().all(|d| (d - bias).abs() < 1e-9));
+        assert!(bias.abs() <= 12.0);
+    }
+}
@@ -0,0 +1,119 @@
+//! 大きな画像をタイルに分割して処理する
+
+use ndarray::{s, Array2, ArrayView2};
+
+/// 画像をタイルに分割して処理し、結果を1枚に結合する
+///
+/// 各タイルは周囲に`halo`画素分の重なりを持った状態で`f`に渡される。
+/// デモザイクなど近傍画素を参照する処理でもタイル境界の結果が変わらないように、
+/// `halo`は処理が参照する近傍の半径以上を指定する。
+/// 結合時は重なり部分を捨て、タイル内部のみを書き戻す。
+///
+/// `f`に渡すビューの始点は常に偶数の行、列に揃えるため、ベイヤー配列の位相は元の画像と変わらない。
+/// そのため始点側の重なりは`halo`より1画素多くなる場合がある。
+///
+/// `tile`は`(height, width)`で指定する。
+///
+/// # Panics
+///
+/// `tile`の縦横どちらかが0の場合、もしくは`f`が入力と異なる大きさの配列を返した場合
+pub fn process_tiles(
+    img: &Array2<u16>,
+    tile: (usize, usize),
+    halo: usize,
+    f: impl Fn(ArrayView2<u16>) -> Array2<u16>,
+) -> Array2<u16> {
+    let (th, tw) = tile;
+    assert!(th > 0 && tw > 0, "tile size must be non-zero");
+    let (h, w) = img.dim();
+    let mut dst = Array2::<u16>::zeros((h, w));
+
+    for y0 in (0..h).step_by(th) {
+        let y1 = (y0 + th).min(h);
+        // ベイヤー配列の位相を保つため偶数に揃える
+        let hy0 = y0.saturating_sub(halo) & !1;
+        let hy1 = (y1 + halo).min(h);
+        for x0 in (0..w).step_by(tw) {
+            let x1 = (x0 + tw).min(w);
+            let hx0 = x0.saturating_sub(halo) & !1;
+            let hx1 = (x1 + halo).min(w);
+
+            let view = img.slice(s![hy0..hy1, hx0..hx1]);
+            let out = f(view);
+            assert_eq!(out.dim(), view.dim(), "tile output shape mismatch");
+            dst.slice_mut(s![y0..y1, x0..x1])
+                .assign(&out.slice(s![y0 - hy0..y1 - hy0, x0 - hx0..x1 - hx0]));
+        }
+    }
+    dst
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{s, Array2, ArrayView2};
+
+    use super::process_tiles;
+    use crate::{demosaic::demosaic_bilinear, BayerPattern};
+
+    // 端を複製する3x3の平均フィルタ
+    fn box3(src: ArrayView2<u16>) -> Array2<u16> {
+        let (h, w) = src.dim();
+        Array2::from_shape_fn((h, w), |(i, j)| {
+            let mut sum = 0_u32;
+            for di in -1_isize..=1 {
+                for dj in -1_isize..=1 {
+                    let y = (i as isize + di).clamp(0, h as isize - 1) as usize;
+                    let x = (j as isize + dj).clamp(0, w as isize - 1) as usize;
+                    sum += src[[y, x]] as u32;
+                }
+            }
+            (sum / 9) as u16
+        })
+    }
+
+    #[test]
+    fn test_process_tiles_matches_whole() {
+        let img = Array2::from_shape_fn((37, 45), |(i, j)| ((i * 31 + j * 17) % 1024) as u16);
+        let whole = box3(img.view());
+
+        for tile in [(8, 8), (16, 7), (37, 45), (64, 64)] {
+            let tiled = process_tiles(&img, tile, 1, box3);
+            assert_eq!(tiled, whole, "tile {:?}", tile);
+        }
+    }
+
+    #[test]
+    fn test_process_tiles_without_halo_differs() {
+        let img = Array2::from_shape_fn((16, 16), |(i, j)| ((i * 31 + j * 17) % 1024) as u16);
+        let whole = box3(img.view());
+        let tiled = process_tiles(&img, (4, 4), 0, box3);
+        assert_ne!(tiled, whole);
+    }
+
+    #[test]
+    fn test_process_tiles_demosaic() {
+        let img = Array2::from_shape_fn((37, 45), |(i, j)| ((i * 31 + j * 17) % 1024) as u16);
+        let ptn = BayerPattern::RGGB;
+        let whole = demosaic_bilinear(&img, ptn);
+
+        // 奇数のタイルサイズや`halo`でもベイヤー配列の位相がずれない
+        for (tile, halo) in [((8, 8), 1), ((7, 7), 2), ((7, 5), 1), ((9, 11), 3)] {
+            for ch in 0..3 {
+                let plane = |v: ArrayView2<u16>| {
+                    demosaic_bilinear(&v.to_owned(), ptn)
+                        .slice(s![.., .., ch])
+                        .to_owned()
+                };
+                let tiled = process_tiles(&img, tile, halo, plane);
+                assert_eq!(
+                    tiled,
+                    whole.slice(s![.., .., ch]),
+                    "tile {:?}, halo {}, ch {}",
+                    tile,
+                    halo,
+                    ch
+                );
+            }
+        }
+    }
+}
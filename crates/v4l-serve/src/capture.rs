@@ -2,9 +2,25 @@
 
 use jetson_pixfmt::{pixfmt::CsiPixelFormat, t16::RawBuffer};
 use rawproc::ImageStack;
-use tokio::{select, sync::mpsc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    select,
+    sync::{mpsc, oneshot, watch},
+};
 use tokio_util::sync::CancellationToken;
-use v4l::{prelude::UserptrStream, video::Capture, Format};
+use v4l::{
+    buffer::Metadata,
+    capability::Flags,
+    control::{self, Value},
+    prelude::{MmapStream, UserptrStream},
+    video::Capture,
+    Format,
+};
 
 use crate::{
     context::{CaptureArgs, Controls, Request},
@@ -21,18 +37,35 @@ pub struct CaptureProp {
     pub controls: Option<Controls>,
     /// カメラの安定を待つバッファ数
     pub buffer_count: u32,
+    /// 切り出す領域
+    pub roi: Option<Roi>,
+    /// 切り出し後の縮小率
+    pub scale: Option<f32>,
 }
 
 impl CaptureProp {
     /// パラメータが有効な範囲内かどうかを検証する
     pub fn validate(&self) -> Result<(), AppError> {
         if self.fourcc.len() != 4 {
-            return Err(anyhow::anyhow!("FourCC must be 4 characters. {}", self.fourcc).into());
+            return Err(AppError::bad_request(anyhow::anyhow!(
+                "FourCC must be 4 characters. {}",
+                self.fourcc
+            )));
         }
         if self.width == 0 || self.height == 0 {
-            return Err(
-                anyhow::anyhow!("Invalid width or height {}x{}", self.width, self.height).into(),
-            );
+            return Err(AppError::bad_request(anyhow::anyhow!(
+                "Invalid width or height {}x{}",
+                self.width,
+                self.height
+            )));
+        }
+        if let Some(scale) = self.scale {
+            if !(scale > 0.0 && scale <= 1.0) {
+                return Err(AppError::bad_request(anyhow::anyhow!(
+                    "Scale must be in (0, 1], got {}",
+                    scale
+                )));
+            }
         }
         Ok(())
     }
@@ -51,22 +84,367 @@ impl CaptureProp {
     }
 }
 
+/// 画像の切り出し領域
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Roi {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Roi {
+    /// 領域がフレーム内に収まっているかを検証する
+    pub fn validate(&self, width: u32, height: u32) -> anyhow::Result<()> {
+        if self.width == 0 || self.height == 0 {
+            anyhow::bail!("Invalid ROI size {}x{}", self.width, self.height);
+        }
+        let inside = self
+            .x
+            .checked_add(self.width)
+            .zip(self.y.checked_add(self.height))
+            .is_some_and(|(r, b)| r <= width && b <= height);
+        if !inside {
+            anyhow::bail!(
+                "ROI {},{} {}x{} is outside of frame {}x{}",
+                self.x,
+                self.y,
+                self.width,
+                self.height,
+                width,
+                height
+            );
+        }
+        Ok(())
+    }
+
+    /// ベイヤー配列の色の並びを保てるよう、原点と大きさが偶数であることを検証する
+    pub fn validate_bayer(&self) -> anyhow::Result<()> {
+        if [self.x, self.y, self.width, self.height]
+            .iter()
+            .any(|v| v % 2 != 0)
+        {
+            anyhow::bail!(
+                "ROI for Bayer raw must have even origin and size, got {},{} {}x{}",
+                self.x,
+                self.y,
+                self.width,
+                self.height
+            );
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Roi {
+    type Err = anyhow::Error;
+
+    /// `x,y,width,height`形式の文字列から変換する
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s
+            .split(',')
+            .map(|x| x.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow::anyhow!("Invalid ROI {}: {}", s, e))?;
+        match v.as_slice() {
+            [x, y, width, height] => Ok(Roi {
+                x: *x,
+                y: *y,
+                width: *width,
+                height: *height,
+            }),
+            _ => Err(anyhow::anyhow!("ROI must be x,y,width,height. {}", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Roi {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// ストリームのI/O方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IoMethod {
+    /// ドライバが確保したバッファをmmapする
+    Mmap,
+    /// ユーザー空間で確保したバッファを渡す
+    ///
+    /// ドライバが対応していない場合は`Mmap`にフォールバックする
+    #[default]
+    UserPtr,
+}
+
+impl std::str::FromStr for IoMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mmap" => Ok(IoMethod::Mmap),
+            "userptr" => Ok(IoMethod::UserPtr),
+            _ => Err(anyhow::anyhow!("Unknown io method: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for IoMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IoMethod::Mmap => write!(f, "mmap"),
+            IoMethod::UserPtr => write!(f, "userptr"),
+        }
+    }
+}
+
+impl IoMethod {
+    /// デバイスがストリーミングI/Oに対応しているかを確認する
+    ///
+    /// どちらの方式に対応しているかはcapabilityからは分からないため、
+    /// バッファの要求に失敗した時点で[`open_with_fallback`]がフォールバックする
+    pub fn check(self, caps: Flags) -> anyhow::Result<()> {
+        if !caps.contains(Flags::STREAMING) {
+            return Err(anyhow::anyhow!(
+                "Device does not support streaming I/O, requested {}",
+                self
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// 指定したI/O方式とバッファ数でストリームを開く
+///
+/// `UserPtr`をドライバが受け付けない(`EINVAL`)場合は警告を出して同じバッファ数の`Mmap`で開き直す。
+/// それ以外のエラーはフォールバックせずにそのまま返す
+pub fn open_with_fallback<S>(
+    io_method: IoMethod,
+    stream_buffers: u32,
+    mut open: impl FnMut(IoMethod, u32) -> std::io::Result<S>,
+) -> anyhow::Result<S> {
+    let mut io_method = io_method;
+    let res = match open(io_method, stream_buffers) {
+        Err(e) if io_method == IoMethod::UserPtr && e.raw_os_error() == Some(libc::EINVAL) => {
+            tracing::warn!(
+                "io method userptr is not supported, fallback to mmap: {:?}",
+                e
+            );
+            io_method = IoMethod::Mmap;
+            open(io_method, stream_buffers)
+        }
+        res => res,
+    };
+    res.map_err(|e| {
+        anyhow::Error::from(e).context(format!(
+            "Failed to request {} {} buffers",
+            stream_buffers, io_method
+        ))
+    })
+}
+
+/// キャプチャルーチンの設定
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureConfig {
+    /// ドライバに要求するストリームバッファ数
+    ///
+    /// 撮影前に捨てるフレーム数(`CaptureArgs::buffer_count`)とは別
+    pub stream_buffers: u32,
+    /// ストリームのI/O方式
+    pub io_method: IoMethod,
+    /// デバイスが失われた場合に再接続を試みる間隔
+    pub reconnect_interval: Duration,
+    /// 再接続を諦めるまでの時間
+    pub reconnect_timeout: Duration,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            stream_buffers: 4,
+            io_method: IoMethod::default(),
+            reconnect_interval: Duration::from_secs(1),
+            reconnect_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl CaptureConfig {
+    /// 設定が有効な範囲内かどうかを検証する
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.stream_buffers == 0 {
+            return Err(anyhow::anyhow!("Stream buffer count must be at least 1"));
+        }
+        if self.reconnect_interval.is_zero() {
+            return Err(anyhow::anyhow!("Reconnect interval must be non-zero"));
+        }
+        if self.reconnect_timeout < self.reconnect_interval {
+            return Err(anyhow::anyhow!(
+                "Reconnect timeout must be longer than the interval"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// デバイスの接続状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureStatus {
+    /// 通常状態
+    Connected,
+    /// デバイスが失われて再接続を待っている
+    Disconnected,
+}
+
+/// デバイスごとの接続状態。一度も失われていないデバイスと再接続を諦めたデバイスは含まれない
+pub type DeviceStatus = HashMap<usize, CaptureStatus>;
+
+// デバイスが再び開けるかどうかを確認する関数
+type Probe = Arc<dyn Fn(usize) -> anyhow::Result<()> + Send + Sync>;
+
+/// エラーがデバイスの取り外しによるものかどうかを判定する
+pub fn is_device_lost(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<std::io::Error>())
+        .any(|e| {
+            matches!(
+                e.raw_os_error(),
+                Some(libc::ENODEV | libc::ENXIO | libc::EIO)
+            )
+        })
+}
+
+/// デバイスが再び開けるようになるまで待つ
+///
+/// 待機中はデバイスの状態を`Disconnected`にし、復帰したら`Connected`に戻す。
+/// タイムアウトもしくはキャンセルされた場合は状態から取り除いて`false`を返す
+async fn wait_reconnect(
+    status: &watch::Sender<DeviceStatus>,
+    device_index: usize,
+    config: &CaptureConfig,
+    token: &CancellationToken,
+    mut open: impl FnMut() -> anyhow::Result<()>,
+) -> bool {
+    tracing::warn!("Device {} is lost, waiting for reconnection", device_index);
+    status.send_modify(|s| {
+        s.insert(device_index, CaptureStatus::Disconnected);
+    });
+    let deadline = tokio::time::Instant::now() + config.reconnect_timeout;
+    let reconnected = loop {
+        select! {
+            _ = token.cancelled() => {
+                break false;
+            }
+            _ = tokio::time::sleep_until(deadline) => {
+                tracing::error!("Gave up reconnecting device {}", device_index);
+                break false;
+            }
+            _ = tokio::time::sleep(config.reconnect_interval) => {
+                match open() {
+                    Ok(_) => {
+                        tracing::info!("Device {} is reconnected", device_index);
+                        break true;
+                    }
+                    Err(e) => {
+                        tracing::debug!("Device {} is not available yet: {:?}", device_index, e);
+                    }
+                }
+            }
+        }
+    };
+    // 待つのをやめたデバイスを`Disconnected`のまま残さない
+    status.send_modify(|s| {
+        if reconnected {
+            s.insert(device_index, CaptureStatus::Connected);
+        } else {
+            s.remove(&device_index);
+        }
+    });
+    reconnected
+}
+
 /// 最終的なcapture実行時のformat
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
 pub struct CaptureFormat {
     pub fourcc: String,
     pub width: u32,
     pub height: u32,
 }
 
+impl From<&Format> for CaptureFormat {
+    fn from(format: &Format) -> Self {
+        CaptureFormat {
+            fourcc: format.fourcc.to_string(),
+            width: format.width,
+            height: format.height,
+        }
+    }
+}
+
+/// 撮影時のコントロールの値
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct ControlValue {
+    pub id: u32,
+    pub value: i64,
+}
+
+/// 撮影したフレームのメタデータ
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct CaptureMeta {
+    pub device_index: usize,
+    /// ドライバが付与するフレームのシーケンス番号
+    pub sequence: u32,
+    /// ドライバが付与するモノトニックなタイムスタンプ(usec)
+    pub timestamp_us: i64,
+    pub format: CaptureFormat,
+    /// 撮影時に読み出したコントロールの値
+    ///
+    /// 要求したものに加え、デバイスが持つ数値のコントロールを全て含む
+    pub controls: Vec<ControlValue>,
+}
+
+impl CaptureMeta {
+    // `time_t`の幅はプラットフォームによって異なるためキャストする
+    #[allow(clippy::unnecessary_cast)]
+    pub fn new(
+        device_index: usize,
+        meta: &Metadata,
+        format: CaptureFormat,
+        controls: Vec<ControlValue>,
+    ) -> Self {
+        CaptureMeta {
+            device_index,
+            sequence: meta.sequence,
+            timestamp_us: meta.timestamp.sec as i64 * 1_000_000 + meta.timestamp.usec as i64,
+            format,
+            controls,
+        }
+    }
+}
+
 pub struct CaptureResponse {
     pub format: CaptureFormat,
     pub buffer: Vec<u8>,
+    /// 最後に取得したフレームのメタデータ
+    pub meta: CaptureMeta,
 }
 
 pub struct CaptureStackResponse {
     pub format: CaptureFormat,
     pub stack: ImageStack,
+    /// 最後に取得したフレームのメタデータ
+    pub meta: CaptureMeta,
+}
+
+pub struct CaptureBurstResponse {
+    pub format: CaptureFormat,
+    pub frames: Vec<Vec<u8>>,
+    /// 最後に取得したフレームのメタデータ
+    pub meta: CaptureMeta,
 }
 
 /// サーバーに対して1つだけのcaptureルーチンを持つ実装
@@ -74,39 +452,79 @@ pub struct CaptureStackResponse {
 /// TODO: 実際には1デバイスあたり1つのルーチンまで実行が許されるので、良き感じに構造化するのが望ましい
 pub struct CaptureRoutine {
     rx: mpsc::Receiver<Request>,
+    config: CaptureConfig,
+    status: watch::Sender<DeviceStatus>,
+    /// デバイスごとに最後に撮影したフレームのメタデータ
+    last_meta: HashMap<usize, CaptureMeta>,
+    /// 再接続を待っているデバイス
+    disconnected: HashSet<usize>,
+    /// 再接続を待つタスクから`(device_index, 復帰したか)`を受け取る
+    reconnect_tx: mpsc::UnboundedSender<(usize, bool)>,
+    reconnect_rx: mpsc::UnboundedReceiver<(usize, bool)>,
+    probe: Probe,
 }
 
 impl CaptureRoutine {
     pub fn new() -> (Self, mpsc::Sender<Request>) {
+        Self::with_config(CaptureConfig::default())
+    }
+
+    /// ストリームの設定を指定して作成する
+    pub fn with_config(config: CaptureConfig) -> (Self, mpsc::Sender<Request>) {
         let (tx, rx) = mpsc::channel(10);
-        (CaptureRoutine { rx }, tx)
+        let (status, _) = watch::channel(DeviceStatus::new());
+        let (reconnect_tx, reconnect_rx) = mpsc::unbounded_channel();
+        (
+            CaptureRoutine {
+                rx,
+                config,
+                status,
+                last_meta: HashMap::new(),
+                disconnected: HashSet::new(),
+                reconnect_tx,
+                reconnect_rx,
+                probe: Arc::new(|index| open_device(index).map(|_| ())),
+            },
+            tx,
+        )
+    }
+
+    /// デバイスの接続状態の変化を購読する
+    pub fn status(&self) -> watch::Receiver<DeviceStatus> {
+        self.status.subscribe()
     }
 
     pub async fn start(&mut self, token: CancellationToken) -> anyhow::Result<()> {
+        self.config.validate()?;
+        let config = self.config;
         loop {
             select! {
+                biased;
                 _ = token.cancelled() => {
                     break;
                 }
+                Some((index, reconnected)) = self.reconnect_rx.recv() => {
+                    self.disconnected.remove(&index);
+                    if reconnected {
+                        tracing::info!("Resume capturing on device {}", index);
+                    } else {
+                        // 次のリクエストで改めてデバイスを開く
+                        tracing::warn!("Device {} did not come back", index);
+                    }
+                }
                 Some(req) = self.rx.recv() => {
                     match req {
                         Request::Capture {
                             tx,
                             args
                         } => {
-                            let res = match capture_inner(args).await{
-                                Ok(res) => res,
-                                Err(e) => {
-                                    tracing::error!("Failed to capture: {:?}", e);
-                                    continue;
-                                }
+                            let index = args.device_index;
+                            let res = match self.connected(index) {
+                                Ok(_) => capture_inner(args, config).await,
+                                Err(e) => Err(e),
                             };
-                            match tx.send(Ok(res)) {
-                                Ok(_) => {}
-                                Err(_e) => {
-                                    tracing::error!("Failed to sendback to connection");
-                                }
-                            }
+                            self.record(res.as_ref().ok().map(|r| &r.meta));
+                            self.reply(tx, res, index, &token);
                         },
                         Request::CaptureAvg {
                             tx,
@@ -114,19 +532,13 @@ impl CaptureRoutine {
                             stack_count,
                             csv_format,
                         } => {
-                            let res = match capture_stack_avg(args, stack_count, csv_format).await{
-                                Ok(res) => res,
-                                Err(e) => {
-                                    tracing::error!("Failed to capture: {:?}", e);
-                                    continue;
-                                }
+                            let index = args.device_index;
+                            let res = match self.connected(index) {
+                                Ok(_) => capture_stack_avg(args, config, stack_count, csv_format).await,
+                                Err(e) => Err(e),
                             };
-                            match tx.send(Ok(res)) {
-                                Ok(_) => {}
-                                Err(_e) => {
-                                    tracing::error!("Failed to sendback to connection");
-                                }
-                            }
+                            self.record(res.as_ref().ok().map(|r| &r.meta));
+                            self.reply(tx, res, index, &token);
                         }
                         Request::CaptureStack {
                             tx,
@@ -134,18 +546,31 @@ impl CaptureRoutine {
                             stack_count,
                             csv_format,
                         } => {
-                            let res = match capture_stack(args, stack_count, csv_format).await{
-                                Ok(res) => res,
-                                Err(e) => {
-                                    tracing::error!("Failed to capture: {:?}", e);
-                                    continue;
-                                }
+                            let index = args.device_index;
+                            let res = match self.connected(index) {
+                                Ok(_) => capture_stack(args, config, stack_count, csv_format).await,
+                                Err(e) => Err(e),
                             };
-                            match tx.send(Ok(res)) {
-                                Ok(_) => {}
-                                Err(_e) => {
-                                    tracing::error!("Failed to sendback to connection");
-                                }
+                            self.record(res.as_ref().ok().map(|r| &r.meta));
+                            self.reply(tx, res, index, &token);
+                        }
+                        Request::CaptureBurst {
+                            tx,
+                            args,
+                            count,
+                        } => {
+                            let index = args.device_index;
+                            let res = match self.connected(index) {
+                                Ok(_) => capture_burst(args, config, count).await,
+                                Err(e) => Err(e),
+                            };
+                            self.record(res.as_ref().ok().map(|r| &r.meta));
+                            self.reply(tx, res, index, &token);
+                        }
+                        Request::LastMeta { tx, device_index } => {
+                            let meta = self.last_meta.get(&device_index).cloned();
+                            if tx.send(meta).is_err() {
+                                tracing::error!("Failed to sendback to connection");
                             }
                         }
                     }
@@ -154,43 +579,109 @@ impl CaptureRoutine {
         }
         Ok(())
     }
+
+    // 再接続を待っているデバイスへのリクエストは待たずにエラーにする
+    fn connected(&self, device_index: usize) -> anyhow::Result<()> {
+        if self.disconnected.contains(&device_index) {
+            return Err(
+                anyhow::Error::from(std::io::Error::from_raw_os_error(libc::ENODEV)).context(
+                    format!(
+                        "Device {} is disconnected, waiting for reconnection",
+                        device_index
+                    ),
+                ),
+            );
+        }
+        Ok(())
+    }
+
+    // 撮影に成功したフレームのメタデータを記録する
+    fn record(&mut self, meta: Option<&CaptureMeta>) {
+        if let Some(meta) = meta {
+            self.last_meta.insert(meta.device_index, meta.clone());
+        }
+    }
+
+    // 結果を返送し、デバイスが失われていれば再接続を待つタスクを起動する
+    //
+    // 再接続を待つ間も他のデバイスへのリクエストは処理を続ける
+    fn reply<T>(
+        &mut self,
+        tx: oneshot::Sender<anyhow::Result<T>>,
+        res: anyhow::Result<T>,
+        device_index: usize,
+        token: &CancellationToken,
+    ) {
+        let lost = match &res {
+            Ok(_) => false,
+            Err(e) => {
+                tracing::error!("Failed to capture: {:?}", e);
+                is_device_lost(e)
+            }
+        };
+        if tx.send(res).is_err() {
+            tracing::error!("Failed to sendback to connection");
+        }
+        if lost && self.disconnected.insert(device_index) {
+            let status = self.status.clone();
+            let config = self.config;
+            let token = token.clone();
+            let reconnect_tx = self.reconnect_tx.clone();
+            let probe = self.probe.clone();
+            tokio::spawn(async move {
+                let reconnected = wait_reconnect(&status, device_index, &config, &token, || {
+                    probe(device_index)
+                })
+                .await;
+                // ルーチンが終了している場合は受け取り手がいない
+                let _ = reconnect_tx.send((device_index, reconnected));
+            });
+        }
+    }
 }
 
 /// captureの内部実装
-async fn capture_inner(carg: CaptureArgs) -> anyhow::Result<CaptureResponse> {
-    use v4l::io::traits::{AsyncCaptureStream, Stream};
-    let (mut stream, actual_format) = open_stream(carg).await?;
+async fn capture_inner(
+    carg: CaptureArgs,
+    config: CaptureConfig,
+) -> anyhow::Result<CaptureResponse> {
+    let index = carg.device_index;
+    let (mut stream, actual_format, controls) = open_stream(carg, config).await?;
 
-    let (buf, _meta) = stream.poll_next().await?;
+    let (buf, meta) = stream.poll_next().await?;
     let b = buf.to_owned();
+    let format = CaptureFormat::from(&actual_format);
+    let meta = CaptureMeta::new(index, meta, format.clone(), controls);
     stream.stop()?;
 
     Ok(CaptureResponse {
-        format: CaptureFormat {
-            fourcc: actual_format.fourcc.to_string(),
-            width: actual_format.width,
-            height: actual_format.height,
-        },
+        format,
         buffer: b,
+        meta,
     })
 }
 
 /// 複数のフレームをキャプチャして平均を取る
 pub async fn capture_stack_avg(
     carg: CaptureArgs,
+    config: CaptureConfig,
     sum_count: usize,
     pixfmt: CsiPixelFormat,
 ) -> anyhow::Result<CaptureResponse> {
-    use v4l::io::traits::{AsyncCaptureStream, Stream};
     let f = jetson_pixfmt::t16::format_copy;
-    let (mut stream, actual_format) = open_stream(carg).await?;
+    let index = carg.device_index;
+    let (mut stream, actual_format, controls) = open_stream(carg, config).await?;
 
-    let (buf, _meta) = stream.poll_next().await?;
+    let (buf, mut last) = {
+        let (buf, meta) = stream.poll_next().await?;
+        (buf, *meta)
+    };
     let mut b = RawBuffer::with_format(buf, pixfmt, f);
     let mut src = RawBuffer::with_format(buf, pixfmt, f);
 
     for _ in 1..sum_count {
-        let (buf, _meta) = stream.poll_next().await?;
+        let (buf, meta) = stream.poll_next().await?;
+        last = *meta;
         src.copy_from_slice_with_format(buf, f);
         b += &src;
     }
@@ -201,12 +692,10 @@ pub async fn capture_stack_avg(
 
     // TODO: 16bit幅のデータに伸張する
 
+    let format = CaptureFormat::from(&actual_format);
     Ok(CaptureResponse {
-        format: CaptureFormat {
-            fourcc: actual_format.fourcc.to_string(),
-            width: actual_format.width,
-            height: actual_format.height,
-        },
+        meta: CaptureMeta::new(index, &last, format.clone(), controls),
+        format,
         buffer: b.into(),
     })
 }
@@ -214,47 +703,125 @@ pub async fn capture_stack_avg(
 /// captureの内部実装
 pub async fn capture_stack(
     carg: CaptureArgs,
+    config: CaptureConfig,
     stack_count: usize,
     pixfmt: CsiPixelFormat,
 ) -> anyhow::Result<CaptureStackResponse> {
-    use v4l::io::traits::{AsyncCaptureStream, Stream};
-    let (mut stream, actual_format) = open_stream(carg).await?;
+    let index = carg.device_index;
+    let (mut stream, actual_format, controls) = open_stream(carg, config).await?;
 
-    let mut stack = {
-        let (buf, _meta) = stream.poll_next().await?;
+    let (mut stack, mut last) = {
+        let (buf, meta) = stream.poll_next().await?;
+        let meta = *meta;
         unsafe {
             let mbuf = std::slice::from_raw_parts_mut(buf.as_ptr() as *mut u8, buf.len());
             jetson_pixfmt::t16::format(mbuf, pixfmt);
         }
         let b = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u16, buf.len() / 2) };
-        ImageStack::from_slice(
+        let stack = ImageStack::from_slice(
             b,
             actual_format.width as usize,
             actual_format.height as usize,
-        )
+        );
+        (stack, meta)
     };
 
     for _ in 1..stack_count {
-        let (buf, _meta) = stream.poll_next().await?;
+        let (buf, meta) = stream.poll_next().await?;
+        last = *meta;
         let b = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u16, buf.len() / 2) };
         stack.push_from_slice(b);
     }
 
     stream.stop()?;
 
+    let format = CaptureFormat::from(&actual_format);
     Ok(CaptureStackResponse {
-        format: CaptureFormat {
-            fourcc: actual_format.fourcc.to_string(),
-            width: actual_format.width,
-            height: actual_format.height,
-        },
+        meta: CaptureMeta::new(index, &last, format.clone(), controls),
+        format,
         stack,
     })
 }
 
+/// 連続したフレームをそのまま取得する
+pub async fn capture_burst(
+    carg: CaptureArgs,
+    config: CaptureConfig,
+    count: usize,
+) -> anyhow::Result<CaptureBurstResponse> {
+    let index = carg.device_index;
+    let (mut stream, actual_format, controls) = open_stream(carg, config).await?;
+
+    let mut frames = Vec::with_capacity(count);
+    let mut last = Metadata::default();
+    for _ in 0..count {
+        let (buf, meta) = stream.poll_next().await?;
+        last = *meta;
+        frames.push(buf.to_owned());
+    }
+
+    stream.stop()?;
+
+    let format = CaptureFormat::from(&actual_format);
+    Ok(CaptureBurstResponse {
+        meta: CaptureMeta::new(index, &last, format.clone(), controls),
+        format,
+        frames,
+    })
+}
+
+/// I/O方式ごとのストリーム
+enum CaptureStream {
+    Mmap(MmapStream<'static>),
+    UserPtr(UserptrStream),
+}
+
+impl CaptureStream {
+    fn new(dev: &v4l::Device, config: CaptureConfig) -> anyhow::Result<Self> {
+        let caps = dev.query_caps().inspect_err(|e| {
+            tracing::error!("Failed to query capabilities: {:?}", e);
+        })?;
+        config.io_method.check(caps.capabilities)?;
+        let typ = v4l::buffer::Type::VideoCapture;
+        open_with_fallback(
+            config.io_method,
+            config.stream_buffers,
+            |io_method, count| match io_method {
+                IoMethod::Mmap => {
+                    MmapStream::with_buffers(dev, typ, count).map(CaptureStream::Mmap)
+                }
+                IoMethod::UserPtr => {
+                    UserptrStream::with_buffers(dev, typ, count).map(CaptureStream::UserPtr)
+                }
+            },
+        )
+        .inspect_err(|e| tracing::error!("Failed to open stream: {:?}", e))
+    }
+
+    async fn poll_next(&mut self) -> std::io::Result<(&[u8], &Metadata)> {
+        use v4l::io::traits::AsyncCaptureStream;
+        match self {
+            CaptureStream::Mmap(s) => s.poll_next().await,
+            CaptureStream::UserPtr(s) => s.poll_next().await,
+        }
+    }
+
+    fn stop(&mut self) -> std::io::Result<()> {
+        use v4l::io::traits::Stream;
+        match self {
+            CaptureStream::Mmap(s) => s.stop(),
+            CaptureStream::UserPtr(s) => s.stop(),
+        }
+    }
+}
+
 // カメラのストリームを開く
-async fn open_stream(carg: CaptureArgs) -> anyhow::Result<(UserptrStream, Format)> {
-    use v4l::io::traits::AsyncCaptureStream;
+//
+// 設定したコントロールは安定を待った後の値を読み出して返す
+async fn open_stream(
+    carg: CaptureArgs,
+    config: CaptureConfig,
+) -> anyhow::Result<(CaptureStream, Format, Vec<ControlValue>)> {
     let CaptureArgs {
         format,
         device_index,
@@ -269,11 +836,21 @@ async fn open_stream(carg: CaptureArgs) -> anyhow::Result<(UserptrStream, Format
         tracing::error!("Failed to get format: {:?}", e);
     })?;
     let Controls { def, target } = controls.unwrap_or(Controls::new(vec![], vec![]));
+    let requested: Vec<u32> = def.iter().chain(target.iter()).map(|c| c.id).collect();
+    let ids = match dev.query_controls() {
+        Ok(descs) => merge_control_ids(
+            &requested,
+            descs.iter().filter(|d| is_numeric(d.typ)).map(|d| d.id),
+        ),
+        Err(e) => {
+            tracing::warn!("Failed to query controls: {:?}", e);
+            merge_control_ids(&requested, [])
+        }
+    };
     if !def.is_empty() {
         dev.set_controls(def)?;
     }
-    let mut stream =
-        UserptrStream::with_buffers(&dev, v4l::buffer::Type::VideoCapture, buffer_count)?;
+    let mut stream = CaptureStream::new(&dev, config)?;
     stream.poll_next().await?;
     if !target.is_empty() {
         dev.set_controls(target)?;
@@ -284,5 +861,364 @@ async fn open_stream(carg: CaptureArgs) -> anyhow::Result<(UserptrStream, Format
             let (_buf, _meta) = stream.poll_next().await?;
         }
     }
-    Ok((stream, actual_format))
+    let controls = read_controls(&dev, &ids);
+    Ok((stream, actual_format, controls))
+}
+
+// メタデータに記録するコントロールのidを決める
+//
+// 露光時間やゲインはドライバ固有のidを持つことが多いため、要求されたものに加えて
+// デバイスが持つ数値のコントロールを全て対象にする
+fn merge_control_ids(requested: &[u32], numeric: impl IntoIterator<Item = u32>) -> Vec<u32> {
+    let mut ids: Vec<u32> = requested.iter().copied().chain(numeric).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
+
+// 値を数値として読み出せるコントロールか
+fn is_numeric(typ: control::Type) -> bool {
+    matches!(
+        typ,
+        control::Type::Integer | control::Type::Integer64 | control::Type::Boolean
+    )
+}
+
+// コントロールの現在値を読み出す。数値で表せないものや読み出しに失敗したものは除く
+fn read_controls(dev: &v4l::Device, ids: &[u32]) -> Vec<ControlValue> {
+    ids.iter()
+        .filter_map(|&id| {
+            let ctrl = dev
+                .control(id)
+                // 書き込み専用のコントロールもあるため、失敗は記録しない
+                .inspect_err(|e| tracing::debug!("Failed to read control {:#x}: {:?}", id, e))
+                .ok()?;
+            let value = match ctrl.value {
+                Value::Integer(v) => v,
+                Value::Boolean(b) => b as i64,
+                _ => return None,
+            };
+            Some(ControlValue { id, value })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use v4l::{buffer::Metadata, capability::Flags, control, Format, FourCC, Timestamp};
+
+    use std::{
+        cell::Cell,
+        io::Error,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+
+    use tokio::sync::{mpsc, oneshot, watch};
+    use tokio_util::sync::CancellationToken;
+
+    use super::{
+        is_device_lost, is_numeric, merge_control_ids, open_with_fallback, wait_reconnect,
+        CaptureConfig, CaptureFormat, CaptureMeta, CaptureRoutine, CaptureStatus, ControlValue,
+        DeviceStatus, IoMethod, Roi,
+    };
+    use crate::{
+        context::{CaptureArgs, Request},
+        error::{AppError, ErrorKind},
+    };
+
+    // ルーチンに最後のメタデータを問い合わせる
+    async fn last_meta(tx: &mpsc::Sender<Request>, device_index: usize) -> Option<CaptureMeta> {
+        let (meta_tx, meta_rx) = oneshot::channel();
+        let req = Request::LastMeta {
+            tx: meta_tx,
+            device_index,
+        };
+        assert!(tx.send(req).await.is_ok());
+        meta_rx.await.unwrap()
+    }
+
+    #[test]
+    fn test_io_method_from_str() {
+        assert_eq!("mmap".parse::<IoMethod>().unwrap(), IoMethod::Mmap);
+        assert_eq!("UserPtr".parse::<IoMethod>().unwrap(), IoMethod::UserPtr);
+        assert!("read".parse::<IoMethod>().is_err());
+        assert!("dmabuf".parse::<IoMethod>().is_err());
+    }
+
+    #[test]
+    fn test_io_method_check() {
+        let caps = Flags::STREAMING | Flags::READ_WRITE;
+        assert!(IoMethod::Mmap.check(caps).is_ok());
+        assert!(IoMethod::UserPtr.check(caps).is_ok());
+        assert!(IoMethod::Mmap.check(Flags::READ_WRITE).is_err());
+    }
+
+    #[test]
+    fn test_open_with_fallback() {
+        // USERPTRに対応していないドライバ
+        let mut requested = vec![];
+        let res = open_with_fallback(IoMethod::UserPtr, 6, |io_method, count| {
+            requested.push((io_method, count));
+            match io_method {
+                IoMethod::UserPtr => Err(Error::from_raw_os_error(libc::EINVAL)),
+                IoMethod::Mmap => Ok(count),
+            }
+        });
+        // フォールバック後も要求したバッファ数で開く
+        assert_eq!(res.unwrap(), 6);
+        assert_eq!(requested, [(IoMethod::UserPtr, 6), (IoMethod::Mmap, 6)]);
+
+        let mut requested = vec![];
+        let res = open_with_fallback(IoMethod::Mmap, 2, |io_method, count| {
+            requested.push((io_method, count));
+            Ok(count)
+        });
+        assert_eq!(res.unwrap(), 2);
+        assert_eq!(requested, [(IoMethod::Mmap, 2)]);
+    }
+
+    #[test]
+    fn test_open_with_fallback_error() {
+        // EINVAL以外はフォールバックしない
+        let mut requested = vec![];
+        let res: anyhow::Result<u32> = open_with_fallback(IoMethod::UserPtr, 4, |io_method, _| {
+            requested.push(io_method);
+            Err(Error::from_raw_os_error(libc::EBUSY))
+        });
+        let err = res.unwrap_err();
+        assert_eq!(requested, [IoMethod::UserPtr]);
+        assert!(err.to_string().contains("4 userptr buffers"), "{}", err);
+
+        // MMAPも失敗する場合はバッファ数を含めて返す
+        let res: anyhow::Result<u32> = open_with_fallback(IoMethod::UserPtr, 4, |_, _| {
+            Err(Error::from_raw_os_error(libc::EINVAL))
+        });
+        let err = res.unwrap_err();
+        assert!(err.to_string().contains("4 mmap buffers"), "{}", err);
+    }
+
+    #[test]
+    fn test_roi() {
+        let roi = "8, 4, 16, 12".parse::<Roi>().unwrap();
+        assert_eq!(
+            roi,
+            Roi {
+                x: 8,
+                y: 4,
+                width: 16,
+                height: 12
+            }
+        );
+        assert!(roi.validate(24, 16).is_ok());
+        assert!(roi.validate(23, 16).is_err());
+        assert!(roi.validate_bayer().is_ok());
+        assert!(Roi { x: 9, ..roi }.validate_bayer().is_err());
+        assert!(Roi { height: 11, ..roi }.validate_bayer().is_err());
+        assert!("1,2,3".parse::<Roi>().is_err());
+        assert!("a,2,3,4".parse::<Roi>().is_err());
+    }
+
+    #[test]
+    fn test_capture_config_validate() {
+        assert!(CaptureConfig::default().validate().is_ok());
+        let config = CaptureConfig {
+            stream_buffers: 0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_merge_control_ids() {
+        // 指定していない露光時間やゲインも記録される
+        let numeric = [0x009a_200a, 0x009a_2009, 0x0098_0913];
+        assert_eq!(
+            merge_control_ids(&[0x009a_2009, 0x009a_0901], numeric),
+            [0x0098_0913, 0x009a_0901, 0x009a_2009, 0x009a_200a]
+        );
+        assert_eq!(merge_control_ids(&[], numeric).len(), 3);
+        assert!(merge_control_ids(&[], []).is_empty());
+
+        assert!(is_numeric(control::Type::Integer));
+        assert!(is_numeric(control::Type::Boolean));
+        assert!(!is_numeric(control::Type::String));
+        assert!(!is_numeric(control::Type::CtrlClass));
+    }
+
+    #[test]
+    fn test_is_device_lost() {
+        let e = anyhow::Error::from(Error::from_raw_os_error(libc::ENODEV));
+        assert!(is_device_lost(&e));
+        let e = anyhow::Error::from(Error::from_raw_os_error(libc::EIO)).context("poll");
+        assert!(is_device_lost(&e));
+        // 存在しないデバイスの指定は取り外しとみなさない
+        let e = anyhow::Error::from(Error::from_raw_os_error(libc::ENOENT));
+        assert!(!is_device_lost(&e));
+        let e = anyhow::Error::from(Error::from_raw_os_error(libc::EINVAL));
+        assert!(!is_device_lost(&e));
+        let e = anyhow::Error::from(Error::other("other"));
+        assert!(!is_device_lost(&e));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait_reconnect() {
+        let (status, rx) = watch::channel(DeviceStatus::new());
+        let token = CancellationToken::new();
+        let count = Cell::new(0);
+        // 2回失敗した後に復帰するデバイス
+        let open = || {
+            count.set(count.get() + 1);
+            if count.get() < 3 {
+                assert_eq!(rx.borrow()[&2], CaptureStatus::Disconnected);
+                Err(Error::from_raw_os_error(libc::ENOENT).into())
+            } else {
+                Ok(())
+            }
+        };
+        let config = CaptureConfig::default();
+        assert!(wait_reconnect(&status, 2, &config, &token, open).await);
+        assert_eq!(count.get(), 3);
+        assert_eq!(rx.borrow()[&2], CaptureStatus::Connected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait_reconnect_cancel() {
+        let (status, rx) = watch::channel(DeviceStatus::new());
+        let token = CancellationToken::new();
+        token.cancel();
+        let open = || Err(Error::from_raw_os_error(libc::ENODEV).into());
+        let config = CaptureConfig::default();
+        assert!(!wait_reconnect(&status, 0, &config, &token, open).await);
+        assert!(!rx.borrow().contains_key(&0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait_reconnect_timeout() {
+        let (status, rx) = watch::channel(DeviceStatus::from([(1, CaptureStatus::Connected)]));
+        let token = CancellationToken::new();
+        let count = Cell::new(0);
+        let open = || {
+            count.set(count.get() + 1);
+            assert_eq!(rx.borrow()[&0], CaptureStatus::Disconnected);
+            Err(Error::from_raw_os_error(libc::ENOENT).into())
+        };
+        let config = CaptureConfig::default();
+        assert!(!wait_reconnect(&status, 0, &config, &token, open).await);
+        assert!(count.get() > 0);
+        // 諦めたデバイスは状態から取り除き、他のデバイスの状態は変えない
+        assert_eq!(
+            *rx.borrow(),
+            DeviceStatus::from([(1, CaptureStatus::Connected)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_last_meta() {
+        let (mut routine, tx) = CaptureRoutine::new();
+        let frame = Metadata {
+            sequence: 42,
+            timestamp: Timestamp { sec: 12, usec: 345 },
+            ..Default::default()
+        };
+        let format = CaptureFormat::from(&Format::new(1920, 1080, FourCC::new(b"RG12")));
+        let gain = ControlValue {
+            id: 0x009a_2009,
+            value: 120,
+        };
+        // 撮影に成功したフレームのメタデータを記録する
+        routine.record(Some(&CaptureMeta::new(
+            0,
+            &frame,
+            format,
+            vec![gain.clone()],
+        )));
+
+        let token = CancellationToken::new();
+        let handle = tokio::spawn({
+            let token = token.clone();
+            async move { routine.start(token).await }
+        });
+
+        let meta = last_meta(&tx, 0).await.unwrap();
+        assert_eq!(meta.sequence, 42);
+        assert_eq!(meta.timestamp_us, 12_000_345);
+        assert_eq!(meta.format.fourcc, "RG12");
+        assert_eq!((meta.format.width, meta.format.height), (1920, 1080));
+        assert_eq!(meta.controls, vec![gain]);
+        // 撮影していないデバイスはない
+        assert!(last_meta(&tx, 1).await.is_none());
+
+        token.cancel();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnect_resume() {
+        let (mut routine, tx) = CaptureRoutine::new();
+        let config = CaptureConfig::default();
+        // 抜かれた後、`available`になるまで開けないデバイス
+        let available = Arc::new(AtomicBool::new(false));
+        routine.probe = {
+            let available = available.clone();
+            Arc::new(move |_| {
+                if available.load(Ordering::SeqCst) {
+                    Ok(())
+                } else {
+                    Err(Error::from_raw_os_error(libc::ENOENT).into())
+                }
+            })
+        };
+        let mut status = routine.status();
+        let token = CancellationToken::new();
+
+        // デバイス0の撮影中に取り外された
+        let (res_tx, res_rx) = oneshot::channel();
+        let lost = anyhow::Error::from(Error::from_raw_os_error(libc::ENODEV)).context("poll");
+        routine.reply::<()>(res_tx, Err(lost), 0, &token);
+        assert!(res_rx.await.unwrap().is_err());
+        status
+            .wait_for(|s| s.get(&0) == Some(&CaptureStatus::Disconnected))
+            .await
+            .unwrap();
+
+        let handle = tokio::spawn({
+            let token = token.clone();
+            async move {
+                routine.start(token).await.unwrap();
+                routine
+            }
+        });
+
+        // 再接続を待つ間も他のデバイスのリクエストは待たされない
+        let start = tokio::time::Instant::now();
+        assert!(last_meta(&tx, 1).await.is_none());
+        // 失われたデバイスへの撮影は再接続を待たずにエラーになる
+        let (res_tx, res_rx) = oneshot::channel();
+        let args = CaptureArgs {
+            device_index: 0,
+            format: Format::new(1920, 1080, FourCC::new(b"RG12")),
+            buffer_count: 4,
+            controls: None,
+        };
+        assert!(tx.send(Request::Capture { tx: res_tx, args }).await.is_ok());
+        let err = AppError::from(res_rx.await.unwrap().err().unwrap());
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(start.elapsed() < config.reconnect_interval);
+
+        // デバイスが戻ると同じルーチンで処理を再開する
+        available.store(true, Ordering::SeqCst);
+        status
+            .wait_for(|s| s.get(&0) == Some(&CaptureStatus::Connected))
+            .await
+            .unwrap();
+        assert!(last_meta(&tx, 0).await.is_none());
+        assert!(!handle.is_finished());
+
+        token.cancel();
+        let routine = handle.await.unwrap();
+        assert!(routine.disconnected.is_empty());
+    }
 }
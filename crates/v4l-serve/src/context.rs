@@ -2,10 +2,40 @@ use jetson_pixfmt::pixfmt::CsiPixelFormat;
 use tokio::sync::{mpsc, oneshot};
 use v4l::Control;
 
-use crate::capture::{CaptureResponse, CaptureStackResponse};
+use crate::{
+    capture::{CaptureBurstResponse, CaptureMeta, CaptureResponse, CaptureStackResponse},
+    lock::DeviceLocks,
+};
 
 pub trait Context {
     fn capture_tx(&self) -> mpsc::Sender<Request>;
+
+    /// デバイスごとのロック
+    ///
+    /// 全てのリクエストで同じロックを共有する必要がある
+    fn device_locks(&self) -> &DeviceLocks;
+
+    /// バースト撮影で一度に取得できる最大枚数
+    fn max_burst_count(&self) -> u32 {
+        16
+    }
+
+    /// ピクセルフォーマットを設定するデバイスを開く
+    fn open_format_device(
+        &self,
+        index: usize,
+    ) -> anyhow::Result<Box<dyn crate::device::FormatDevice>> {
+        Ok(Box::new(crate::util::open_device(index)?))
+    }
+
+    /// LUTを適用するデバイスを開く
+    #[cfg(feature = "lut")]
+    fn open_control_device(
+        &self,
+        index: usize,
+    ) -> anyhow::Result<Box<dyn crate::lut::ControlDevice>> {
+        Ok(Box::new(crate::util::open_device(index)?))
+    }
 }
 
 pub enum Request {
@@ -25,6 +55,16 @@ pub enum Request {
         stack_count: usize,
         csv_format: CsiPixelFormat,
     },
+    CaptureBurst {
+        tx: oneshot::Sender<Result<CaptureBurstResponse, anyhow::Error>>,
+        args: CaptureArgs,
+        count: usize,
+    },
+    /// 最後に撮影したフレームのメタデータを取得する
+    LastMeta {
+        tx: oneshot::Sender<Option<CaptureMeta>>,
+        device_index: usize,
+    },
 }
 
 /// カメラのコントロールの設定
@@ -43,6 +83,7 @@ impl Controls {
 pub struct CaptureArgs {
     pub device_index: usize,
     pub format: v4l::format::Format,
+    /// カメラの安定を待つために捨てるフレーム数
     pub buffer_count: u32,
     pub controls: Option<Controls>,
 }
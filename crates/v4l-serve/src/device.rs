@@ -2,23 +2,23 @@ use std::{io::BufWriter, path::PathBuf, time::Duration};
 
 use axum::{
     body::Body,
-    extract::{Path, Query, State},
+    extract::State,
     http::{HeaderMap, HeaderName},
     response::IntoResponse,
-    Json,
 };
 use image::ImageEncoder;
 use jetson_pixfmt::pixfmt::CsiPixelFormat;
 use v4l::{
     util::control::{ControlTable, ControlTexts},
     video::Capture,
-    Format,
+    Format, FourCC,
 };
 
 use crate::{
-    capture::{CaptureFormat, CaptureProp, CaptureResponse},
+    capture::{CaptureBurstResponse, CaptureFormat, CaptureProp, CaptureResponse, Roi},
     context::{CaptureArgs, Context, Controls, Request},
-    error::AppError,
+    error::{AppError, ErrorKind},
+    extract::{Json, Path, Query},
     util::open_device,
 };
 
@@ -130,20 +130,96 @@ impl From<v4l::framesize::Discrete> for Discrete {
     }
 }
 
+/// fourcc文字列で表したピクセルフォーマット
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PixelFormat {
+    pub fourcc: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl PixelFormat {
+    /// デバイスが対応しているfourccかを検証してv4lのフォーマットに変換する
+    pub fn to_format(&self, supported: &[FourCC]) -> Result<Format, AppError> {
+        let repr: [u8; 4] = self.fourcc.as_bytes().try_into().map_err(|_| {
+            AppError::bad_request(anyhow::anyhow!(
+                "FourCC must be 4 characters. {}",
+                self.fourcc
+            ))
+        })?;
+        if self.width == 0 || self.height == 0 {
+            return Err(AppError::bad_request(anyhow::anyhow!(
+                "Invalid width or height {}x{}",
+                self.width,
+                self.height
+            )));
+        }
+        let fourcc = FourCC::new(&repr);
+        if !supported.contains(&fourcc) {
+            let supported: Vec<String> = supported.iter().map(|f| f.to_string()).collect();
+            return Err(AppError::unsupported_format(anyhow::anyhow!(
+                "Unsupported fourcc: {}, supported: [{}]",
+                self.fourcc,
+                supported.join(", ")
+            )));
+        }
+        Ok(Format::new(self.width, self.height, fourcc))
+    }
+}
+
+impl From<Format> for PixelFormat {
+    fn from(format: Format) -> Self {
+        PixelFormat {
+            fourcc: format.fourcc.to_string(),
+            width: format.width,
+            height: format.height,
+        }
+    }
+}
+
+/// ピクセルフォーマットの一覧の取得と設定ができるデバイス
+pub trait FormatDevice {
+    /// デバイスが対応しているfourcc
+    fn fourccs(&self) -> std::io::Result<Vec<FourCC>>;
+
+    /// フォーマットを設定し、ドライバによって調整された実際のフォーマットを返す
+    fn set_format(&self, format: &Format) -> std::io::Result<Format>;
+}
+
+impl FormatDevice for v4l::Device {
+    fn fourccs(&self) -> std::io::Result<Vec<FourCC>> {
+        Ok(self
+            .enum_formats()?
+            .into_iter()
+            .map(|desc| desc.fourcc)
+            .collect())
+    }
+
+    fn set_format(&self, format: &Format) -> std::io::Result<Format> {
+        Capture::set_format(self, format)
+    }
+}
+
+/// 撮影APIで共通のクエリ
 #[derive(Debug, serde::Deserialize)]
-pub struct CaptureQuery {
+pub struct CaptureCommonQuery {
     pub fourcc: Option<String>,
+    #[serde(default, deserialize_with = "from_query_str_opt")]
     pub width: Option<u32>,
+    #[serde(default, deserialize_with = "from_query_str_opt")]
     pub height: Option<u32>,
     pub control: Option<String>,
     /// カメラの安定を待つバッファ数
-    #[serde(default = "CaptureQuery::buffer_count_default")]
+    #[serde(
+        default = "CaptureCommonQuery::buffer_count_default",
+        deserialize_with = "from_query_str"
+    )]
     pub buffer_count: u32,
-    #[serde(default = "OutFmt::default")]
+    #[serde(default = "OutFmt::default", alias = "format")]
     pub outfmt: OutFmt,
 }
 
-impl CaptureQuery {
+impl CaptureCommonQuery {
     fn buffer_count_default() -> u32 {
         4
     }
@@ -156,46 +232,85 @@ impl CaptureQuery {
             height: self.height.unwrap_or(format.height),
             controls: ctrls,
             buffer_count: self.buffer_count,
+            roi: None,
+            scale: None,
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CaptureQuery {
+    #[serde(flatten)]
+    pub common: CaptureCommonQuery,
+    /// 切り出す領域(`x,y,width,height`)
+    pub roi: Option<Roi>,
+    /// 切り出し後の縮小率
+    pub scale: Option<f32>,
+}
+
+impl CaptureQuery {
+    /// クエリの他、未入力の場合はデバイスデフォルトの値を使用してCapturePropを生成する
+    pub fn to_prop(&self, format: v4l::format::Format, ctrls: Option<Controls>) -> CaptureProp {
+        CaptureProp {
+            roi: self.roi,
+            scale: self.scale,
+            ..self.common.to_prop(format, ctrls)
         }
     }
 }
 
 #[derive(Debug, serde::Deserialize)]
 pub struct CaptureStackQuery {
-    pub fourcc: Option<String>,
-    pub width: Option<u32>,
-    pub height: Option<u32>,
-    pub control: Option<String>,
-    /// カメラの安定を待つバッファ数
-    #[serde(default = "CaptureStackQuery::buffer_count_default")]
-    pub buffer_count: u32,
-    #[serde(default = "OutFmt::default")]
-    pub outfmt: OutFmt,
+    #[serde(flatten)]
+    pub common: CaptureCommonQuery,
     #[serde(default = "CaptureStackQuery::buffer_stack_default")]
     pub stack_count: u32,
 }
 
 impl CaptureStackQuery {
-    fn buffer_count_default() -> u32 {
-        4
-    }
-
     fn buffer_stack_default() -> u32 {
         5
     }
+}
 
-    /// クエリの他、未入力の場合はデバイスデフォルトの値を使用してCapturePropを生成する
-    pub fn to_prop(&self, format: v4l::format::Format, ctrls: Option<Controls>) -> CaptureProp {
-        CaptureProp {
-            fourcc: self.fourcc.clone().unwrap_or(format.fourcc.to_string()),
-            width: self.width.unwrap_or(format.width),
-            height: self.height.unwrap_or(format.height),
-            controls: ctrls,
-            buffer_count: self.buffer_count,
-        }
+#[derive(Debug, serde::Deserialize)]
+pub struct CaptureBurstQuery {
+    #[serde(flatten)]
+    pub common: CaptureCommonQuery,
+    /// 撮影枚数
+    #[serde(default = "CaptureBurstQuery::count_default")]
+    pub count: u32,
+}
+
+impl CaptureBurstQuery {
+    fn count_default() -> u32 {
+        3
     }
 }
 
+// `#[serde(flatten)]`で埋め込まれる側には値が文字列のまま渡されるため、`FromStr`で変換する
+fn from_query_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+fn from_query_str_opt<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    <Option<String> as serde::Deserialize>::deserialize(deserializer)?
+        .map(|s| s.parse())
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
 /// RAW画像の出力フォーマット
 #[derive(Debug, Default, PartialEq, serde::Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -229,7 +344,14 @@ pub async fn list() -> Result<impl IntoResponse, AppError> {
 }
 
 // get device and show controls
-pub async fn device(Path(index): Path<usize>) -> Result<impl IntoResponse, AppError> {
+pub async fn device<C>(
+    State(context): State<C>,
+    Path(index): Path<usize>,
+) -> Result<impl IntoResponse, AppError>
+where
+    C: Context,
+{
+    let _guard = context.device_locks().read(index).await?;
     let dev = open_device(index)?;
     let cap = dev.query_controls().inspect_err(|e| {
         tracing::error!("Failed to query controls: {:?}", e);
@@ -255,6 +377,74 @@ pub async fn device(Path(index): Path<usize>) -> Result<impl IntoResponse, AppEr
     Ok(Json(DeviceDetail { controls, formats }))
 }
 
+/// 現在のピクセルフォーマットを取得する
+pub async fn get_format<C>(
+    State(context): State<C>,
+    Path(index): Path<usize>,
+) -> Result<impl IntoResponse, AppError>
+where
+    C: Context,
+{
+    let _guard = context.device_locks().read(index).await?;
+    let dev = open_device(index)?;
+    let format = dev.format().inspect_err(|e| {
+        tracing::error!("Failed to get format: {:?}", e);
+    })?;
+    Ok(Json(PixelFormat::from(format)))
+}
+
+/// fourccで指定したピクセルフォーマットを設定する
+///
+/// ドライバによって調整された実際のフォーマットを返す
+pub async fn set_format<C>(
+    State(context): State<C>,
+    Path(index): Path<usize>,
+    Json(req): Json<PixelFormat>,
+) -> Result<impl IntoResponse, AppError>
+where
+    C: Context,
+{
+    let _guard = context.device_locks().write(index).await?;
+    let dev = context.open_format_device(index)?;
+    let supported = dev.fourccs().inspect_err(|e| {
+        tracing::error!("Failed to query format: {:?}", e);
+    })?;
+    let format = req.to_format(&supported)?;
+    let actual = dev.set_format(&format).inspect_err(|e| {
+        tracing::error!("Failed to set format: {:?}", e);
+    })?;
+    tracing::info!("Set format: {} -> {}", format, actual);
+    Ok(Json(PixelFormat::from(actual)))
+}
+
+/// 最後に撮影したフレームのメタデータを取得する
+pub async fn last_meta<C>(
+    State(context): State<C>,
+    Path(index): Path<usize>,
+) -> Result<impl IntoResponse, AppError>
+where
+    C: Context,
+{
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let req = Request::LastMeta {
+        tx,
+        device_index: index,
+    };
+    context.capture_tx().send(req).await.inspect_err(|e| {
+        tracing::error!("Failed to send last meta request: {:?}", e);
+    })?;
+    let meta = rx.await.inspect_err(|e| {
+        tracing::error!("Failed to receive last meta response: {:?}", e);
+    })?;
+    let Some(meta) = meta else {
+        return Err(AppError::new(
+            ErrorKind::NotFound,
+            anyhow::anyhow!("No frame has been captured on device {}", index),
+        ));
+    };
+    Ok(Json(meta))
+}
+
 /// Capture image from device
 pub async fn capture<C>(
     State(context): State<C>,
@@ -264,8 +454,9 @@ pub async fn capture<C>(
 where
     C: Context,
 {
-    let (default_format, ctrls, ctrl_test) =
-        fetch_format(index, &query.0.control).inspect_err(|e| {
+    let _guard = context.device_locks().write(index).await?;
+    let (default_format, ctrls, ctrl_test) = fetch_format(index, &query.0.common.control)
+        .inspect_err(|e| {
             tracing::error!("Failed to fetch format: {:?}", e);
         })?;
 
@@ -273,6 +464,13 @@ where
     prop.validate()?;
     tracing::info!("Capture: {:?}", prop);
     let format = prop.format();
+    let (roi, scale) = (prop.roi, prop.scale);
+    if (roi.is_some() || scale.is_some()) && query.0.common.outfmt != OutFmt::Png {
+        return Err(AppError::bad_request(anyhow::anyhow!(
+            "roi and scale require outfmt=png"
+        )));
+    }
+    check_crop(&prop.fourcc, prop.width, prop.height, roi, scale)?;
 
     // デバイスを開く操作は1つだけしか許されないため
     // Captureは別の単一フローのルーチンで取得する
@@ -298,37 +496,26 @@ where
     if let Some(ctrl_test) = ctrl_test {
         header_from_ctrl_text(&mut headers, &ctrl_test);
     }
+    // ドライバが要求と異なるフォーマットを返した場合もここで確認する
+    check_crop(
+        &res.format.fourcc,
+        res.format.width,
+        res.format.height,
+        roi,
+        scale,
+    )?;
 
     if res.format.fourcc == "MJPG" {
         headers.insert("Content-Type", "image/jpeg".parse().unwrap());
-    } else if query.0.outfmt == OutFmt::Png {
+    } else if query.0.common.outfmt == OutFmt::Png {
         match res.format.fourcc.as_str() {
             "YUYV" => {
                 headers.insert("Content-Type", "image/png".parse().unwrap());
-                let rgb =
-                    crate::imgfmt::yuyv422_to_rgb(&res.buffer, res.format.width, res.format.height)
-                        .inspect_err(|e| {
-                            tracing::error!("Failed to convert YUYV to RGB: {:?}", e);
-                        })?;
-                let img =
-                    image::RgbImage::from_raw(res.format.width, res.format.height, rgb).unwrap();
-                // clear buffer and encode PNG
-                res.buffer.clear();
-                let writer = BufWriter::new(&mut res.buffer);
-                let enc = image::codecs::png::PngEncoder::new(writer);
-                enc.write_image(
-                    img.as_raw(),
-                    img.width(),
-                    img.height(),
-                    image::ExtendedColorType::Rgb8,
-                )
-                .inspect_err(|e| {
-                    tracing::error!("Failed to encode PNG: {:?}", e);
-                })?;
+                format_yuyv_to_png(&mut res, roi, scale)?;
             }
             "RG10" | "RG12" => {
                 headers.insert("Content-Type", "image/png".parse().unwrap());
-                format_raw_to_png(&mut res).inspect_err(|e| {
+                format_raw_to_png(&mut res, roi).inspect_err(|e| {
                     tracing::error!("Failed to format raw: {:?}", e);
                 })?;
             }
@@ -336,6 +523,8 @@ where
                 headers.insert("Content-Type", "image/raw".parse().unwrap());
             }
         }
+        // 切り出し後の大きさを返す
+        header_from_size(&mut headers, res.format.width, res.format.height);
     } else {
         headers.insert("Content-Type", "image/raw".parse().unwrap());
     }
@@ -354,12 +543,13 @@ pub async fn capture_stack_avg<C>(
 where
     C: Context,
 {
-    let (default_format, ctrls, ctrl_test) =
-        fetch_format(index, &query.0.control).inspect_err(|e| {
+    let _guard = context.device_locks().write(index).await?;
+    let (default_format, ctrls, ctrl_test) = fetch_format(index, &query.0.common.control)
+        .inspect_err(|e| {
             tracing::error!("Failed to fetch format: {:?}", e);
         })?;
 
-    let prop = query.0.to_prop(default_format, ctrls);
+    let prop = query.0.common.to_prop(default_format, ctrls);
     prop.validate()?;
     tracing::info!("Capture: {:?}", prop);
     let format = prop.format();
@@ -367,7 +557,12 @@ where
     let csv_format = match format.fourcc.str()? {
         "RG10" => CsiPixelFormat::Raw10,
         "RG12" => CsiPixelFormat::Raw12,
-        _ => Err(anyhow::anyhow!("Unsupported fourcc: {}", format.fourcc))?,
+        _ => {
+            return Err(AppError::unsupported_format(anyhow::anyhow!(
+                "Unsupported fourcc: {}",
+                format.fourcc
+            )))
+        }
     };
 
     // デバイスを開く操作は1つだけしか許されないため
@@ -403,7 +598,7 @@ where
         query.0.stack_count.to_string().parse().unwrap(),
     );
 
-    if query.0.outfmt == OutFmt::Png {
+    if query.0.common.outfmt == OutFmt::Png {
         match res.format.fourcc.as_str() {
             "RG10" | "RG12" => {
                 headers.insert("Content-Type", "image/png".parse().unwrap());
@@ -432,12 +627,13 @@ pub async fn capture_stack_std<C>(
 where
     C: Context,
 {
-    let (default_format, ctrls, ctrl_test) =
-        fetch_format(index, &query.0.control).inspect_err(|e| {
+    let _guard = context.device_locks().write(index).await?;
+    let (default_format, ctrls, ctrl_test) = fetch_format(index, &query.0.common.control)
+        .inspect_err(|e| {
             tracing::error!("Failed to fetch format: {:?}", e);
         })?;
 
-    let prop = query.0.to_prop(default_format, ctrls);
+    let prop = query.0.common.to_prop(default_format, ctrls);
     prop.validate()?;
     tracing::info!("Capture: {:?}", prop);
     let format = prop.format();
@@ -445,7 +641,12 @@ where
     let csv_format = match format.fourcc.str()? {
         "RG10" => CsiPixelFormat::Raw10,
         "RG12" => CsiPixelFormat::Raw12,
-        _ => Err(anyhow::anyhow!("Unsupported fourcc: {}", format.fourcc))?,
+        _ => {
+            return Err(AppError::unsupported_format(anyhow::anyhow!(
+                "Unsupported fourcc: {}",
+                format.fourcc
+            )))
+        }
     };
 
     // デバイスを開く操作は1つだけしか許されないため
@@ -493,7 +694,7 @@ where
         buf
     };
 
-    if query.0.outfmt == OutFmt::Png {
+    if query.0.common.outfmt == OutFmt::Png {
         match res.format.fourcc.as_str() {
             "RG10" | "RG12" => {
                 headers.insert("Content-Type", "image/png".parse().unwrap());
@@ -521,8 +722,180 @@ where
     Ok((headers, Body::from(buf)))
 }
 
+/// Burst capture
+///
+/// 連続した画像を取得してZIPにまとめて返す
+pub async fn capture_burst<C>(
+    State(context): State<C>,
+    Path(index): Path<usize>,
+    query: Query<CaptureBurstQuery>,
+) -> Result<impl IntoResponse, AppError>
+where
+    C: Context,
+{
+    let max = context.max_burst_count();
+    if query.0.count == 0 || query.0.count > max {
+        return Err(AppError::bad_request(anyhow::anyhow!(
+            "count must be in 1..={}, got {}",
+            max,
+            query.0.count
+        )));
+    }
+    let _guard = context.device_locks().write(index).await?;
+    let (default_format, ctrls, ctrl_test) = fetch_format(index, &query.0.common.control)
+        .inspect_err(|e| {
+            tracing::error!("Failed to fetch format: {:?}", e);
+        })?;
+
+    let prop = query.0.common.to_prop(default_format, ctrls);
+    prop.validate()?;
+    tracing::info!("Capture: {:?}", prop);
+    let format = prop.format();
+
+    // デバイスを開く操作は1つだけしか許されないため
+    // Captureは別の単一フローのルーチンで取得する
+    let args = CaptureArgs {
+        device_index: index,
+        format,
+        buffer_count: prop.buffer_count,
+        controls: prop.controls,
+    };
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let req = Request::CaptureBurst {
+        tx,
+        args,
+        count: query.0.count as usize,
+    };
+    let start = tokio::time::Instant::now();
+    context.capture_tx().send(req).await.inspect_err(|e| {
+        tracing::error!("Failed to send capture request: {:?}", e);
+    })?;
+    let res = rx.await.inspect_err(|e| {
+        tracing::error!("Failed to receive capture response: {:?}", e);
+    })??;
+    let mut headers = HeaderMap::new();
+    header_from_format(&mut headers, &res.format, start.elapsed());
+    if let Some(ctrl_test) = ctrl_test {
+        header_from_ctrl_text(&mut headers, &ctrl_test);
+    }
+    headers.insert(
+        "X-Burst-Count",
+        res.frames.len().to_string().parse().unwrap(),
+    );
+
+    let zip = burst_to_zip(res, &query.0.common.outfmt)?;
+    headers.insert("Content-Type", "application/zip".parse().unwrap());
+    Ok((headers, Body::from(zip)))
+}
+
+// 撮影したフレームを1枚ずつファイルにしてZIPにまとめる
+fn burst_to_zip(res: CaptureBurstResponse, outfmt: &OutFmt) -> anyhow::Result<Vec<u8>> {
+    let mut files = Vec::with_capacity(res.frames.len());
+    for (i, buffer) in res.frames.into_iter().enumerate() {
+        let mut frame = CaptureResponse {
+            format: res.format.clone(),
+            buffer,
+            meta: res.meta.clone(),
+        };
+        let ext = match (frame.format.fourcc.as_str(), outfmt) {
+            ("MJPG", _) => "jpg",
+            ("YUYV", OutFmt::Png) => {
+                format_yuyv_to_png(&mut frame, None, None)?;
+                "png"
+            }
+            ("RG10" | "RG12", OutFmt::Png) => {
+                format_raw_to_png(&mut frame, None).inspect_err(|e| {
+                    tracing::error!("Failed to format raw: {:?}", e);
+                })?;
+                "png"
+            }
+            _ => "raw",
+        };
+        files.push((format!("frame_{:03}.{}", i, ext), frame.buffer));
+    }
+    crate::imgfmt::zip_files(files).inspect_err(|e| {
+        tracing::error!("Failed to create zip: {:?}", e);
+    })
+}
+
+// YUYV画像をRGBのpngに変換する
+fn format_yuyv_to_png(
+    res: &mut CaptureResponse,
+    roi: Option<Roi>,
+    scale: Option<f32>,
+) -> anyhow::Result<()> {
+    let rgb = crate::imgfmt::yuyv422_to_rgb(&res.buffer, res.format.width, res.format.height)
+        .inspect_err(|e| {
+            tracing::error!("Failed to convert YUYV to RGB: {:?}", e);
+        })?;
+    let img = image::RgbImage::from_raw(res.format.width, res.format.height, rgb).unwrap();
+    let img = crate::imgfmt::crop_scale(img, roi, scale)?;
+    res.format.width = img.width();
+    res.format.height = img.height();
+    // clear buffer and encode PNG
+    res.buffer.clear();
+    let writer = BufWriter::new(&mut res.buffer);
+    let enc = image::codecs::png::PngEncoder::new(writer);
+    enc.write_image(
+        img.as_raw(),
+        img.width(),
+        img.height(),
+        image::ExtendedColorType::Rgb8,
+    )
+    .inspect_err(|e| {
+        tracing::error!("Failed to encode PNG: {:?}", e);
+    })?;
+    Ok(())
+}
+
+// 切り出しと縮小の指定がfourccとフレームの大きさに対して有効かを確認する
+//
+// 切り出しに対応していないfourccでは指定を無視せずにエラーにする
+fn check_crop(
+    fourcc: &str,
+    width: u32,
+    height: u32,
+    roi: Option<Roi>,
+    scale: Option<f32>,
+) -> Result<(), AppError> {
+    if roi.is_none() && scale.is_none() {
+        return Ok(());
+    }
+    match fourcc {
+        "YUYV" => {}
+        "RG10" | "RG12" => check_raw_crop(roi, scale)?,
+        _ => {
+            return Err(AppError::unsupported_format(anyhow::anyhow!(
+                "roi and scale are not supported for fourcc {}, only YUYV, RG10 or RG12",
+                fourcc
+            )))
+        }
+    }
+    if let Some(roi) = roi {
+        roi.validate(width, height).map_err(AppError::bad_request)?;
+    }
+    Ok(())
+}
+
+// RAW画像の切り出し指定がベイヤー配列の位相を保てるかを確認する
+//
+// モザイクのまま縮小すると異なる色の画素が混ざるため、縮小は受け付けない
+fn check_raw_crop(roi: Option<Roi>, scale: Option<f32>) -> Result<(), AppError> {
+    if scale.is_some() {
+        return Err(AppError::bad_request(anyhow::anyhow!(
+            "scale is not supported for Bayer raw fourcc"
+        )));
+    }
+    if let Some(roi) = roi {
+        roi.validate_bayer().map_err(AppError::bad_request)?;
+    }
+    Ok(())
+}
+
 // キャプチャ画像を16bitグレースケールに適した値域でpngに変換する
-fn format_raw_to_png(res: &mut CaptureResponse) -> anyhow::Result<()> {
+//
+// `roi`は`check_raw_crop`で確認済みのものを渡す
+fn format_raw_to_png(res: &mut CaptureResponse, roi: Option<Roi>) -> anyhow::Result<()> {
     let pixfmt = match res.format.fourcc.as_str() {
         "RG10" => CsiPixelFormat::Raw10,
         "RG12" => CsiPixelFormat::Raw12,
@@ -536,6 +909,23 @@ fn format_raw_to_png(res: &mut CaptureResponse) -> anyhow::Result<()> {
     // 16bit空間に12bitを展開するため左シフトして16bit領域全体を使う
     // jetsonのRG12は左詰めされているので下位をマスクする
     jetson_pixfmt::t16::mask(&mut res.buffer, pixfmt);
+    if let Some(roi) = roi {
+        let data = res
+            .buffer
+            .chunks_exact(2)
+            .map(|b| u16::from_ne_bytes([b[0], b[1]]))
+            .collect();
+        let img = image::ImageBuffer::<image::Luma<u16>, _>::from_raw(
+            res.format.width,
+            res.format.height,
+            data,
+        )
+        .ok_or_else(|| anyhow::anyhow!("Buffer is smaller than the frame size"))?;
+        let img = crate::imgfmt::crop_scale(img, Some(roi), None)?;
+        res.format.width = img.width();
+        res.format.height = img.height();
+        res.buffer = img.as_raw().iter().flat_map(|v| v.to_ne_bytes()).collect();
+    }
     let mut out = vec![];
     let writer = BufWriter::new(&mut out);
 
@@ -618,6 +1008,12 @@ fn header_from_format(headers: &mut HeaderMap, format: &CaptureFormat, elapsed:
     );
 }
 
+// 出力画像の大きさをヘッダに反映する
+fn header_from_size(headers: &mut HeaderMap, width: u32, height: u32) {
+    headers.insert("X-Image-Width", width.to_string().parse().unwrap());
+    headers.insert("X-Image-Height", height.to_string().parse().unwrap());
+}
+
 // コントロール情報をヘッダに追加する
 fn header_from_ctrl_text(headers: &mut HeaderMap, text: &ControlTexts) {
     for (key, value) in text.0.iter() {
@@ -627,3 +1023,361 @@ fn header_from_ctrl_text(headers: &mut HeaderMap, text: &ControlTexts) {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        body::Body,
+        extract::Query,
+        http::{header, Request as HttpRequest, StatusCode, Uri},
+        Router,
+    };
+    use tokio::sync::mpsc;
+    use tower::ServiceExt;
+    use v4l::{buffer::Metadata, Format, FourCC};
+
+    use super::{
+        burst_to_zip, check_crop, check_raw_crop, format_raw_to_png, CaptureBurstQuery,
+        CaptureQuery, CaptureStackQuery, FormatDevice, OutFmt, PixelFormat,
+    };
+    use crate::{
+        capture::{CaptureBurstResponse, CaptureFormat, CaptureMeta, CaptureResponse, Roi},
+        context::{Context, Request},
+        error::ErrorKind,
+        lock::DeviceLocks,
+    };
+
+    // 要求された大きさに関わらず、センサーのモードに合わせた大きさに調整するデバイス
+    #[derive(Clone)]
+    struct MockFormatDevice {
+        supported: Vec<FourCC>,
+        mode: (u32, u32),
+        requested: Arc<Mutex<Vec<(u32, u32)>>>,
+    }
+
+    impl FormatDevice for MockFormatDevice {
+        fn fourccs(&self) -> std::io::Result<Vec<FourCC>> {
+            Ok(self.supported.clone())
+        }
+
+        fn set_format(&self, format: &Format) -> std::io::Result<Format> {
+            let mut requested = self.requested.lock().unwrap();
+            requested.push((format.width, format.height));
+            Ok(Format::new(self.mode.0, self.mode.1, format.fourcc))
+        }
+    }
+
+    #[derive(Clone)]
+    struct TestContext {
+        capture_tx: mpsc::Sender<Request>,
+        device_locks: DeviceLocks,
+        format_device: MockFormatDevice,
+    }
+
+    impl TestContext {
+        fn new(capture_tx: mpsc::Sender<Request>) -> Self {
+            TestContext {
+                capture_tx,
+                device_locks: DeviceLocks::default(),
+                format_device: MockFormatDevice {
+                    supported: vec![FourCC::new(b"RG10"), FourCC::new(b"RG12")],
+                    mode: (1936, 1096),
+                    requested: Arc::new(Mutex::new(vec![])),
+                },
+            }
+        }
+    }
+
+    impl Context for TestContext {
+        fn capture_tx(&self) -> mpsc::Sender<Request> {
+            self.capture_tx.clone()
+        }
+
+        fn device_locks(&self) -> &DeviceLocks {
+            &self.device_locks
+        }
+
+        fn max_burst_count(&self) -> u32 {
+            4
+        }
+
+        fn open_format_device(&self, _index: usize) -> anyhow::Result<Box<dyn FormatDevice>> {
+            Ok(Box::new(self.format_device.clone()))
+        }
+    }
+
+    fn query<T: serde::de::DeserializeOwned>(q: &str) -> Result<T, String> {
+        let uri: Uri = format!("http://localhost/?{}", q).parse().unwrap();
+        Query::<T>::try_from_uri(&uri)
+            .map(|q| q.0)
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_pixel_format_to_format() {
+        let supported = [FourCC::new(b"RG10"), FourCC::new(b"RG12")];
+        let req: PixelFormat =
+            serde_json::from_str(r#"{"fourcc": "RG12", "width": 1920, "height": 1080}"#).unwrap();
+        let format = req.to_format(&supported).unwrap();
+        assert_eq!(format.fourcc, FourCC::new(b"RG12"));
+        assert_eq!((format.width, format.height), (1920, 1080));
+
+        // ドライバが調整したフォーマットがそのままレスポンスになる
+        let actual = Format::new(1936, 1096, format.fourcc);
+        assert_eq!(
+            serde_json::to_value(PixelFormat::from(actual)).unwrap(),
+            serde_json::json!({"fourcc": "RG12", "width": 1936, "height": 1096})
+        );
+    }
+
+    #[test]
+    fn test_pixel_format_invalid() {
+        let supported = [FourCC::new(b"RG10")];
+        let req = |fourcc: &str, width, height| PixelFormat {
+            fourcc: fourcc.to_string(),
+            width,
+            height,
+        };
+        let kind = |req: PixelFormat| req.to_format(&supported).unwrap_err().kind();
+        assert_eq!(kind(req("RG1", 1920, 1080)), ErrorKind::BadRequest);
+        assert_eq!(kind(req("RG10", 0, 1080)), ErrorKind::BadRequest);
+        assert_eq!(kind(req("YUYV", 1920, 1080)), ErrorKind::UnsupportedFormat);
+    }
+
+    #[tokio::test]
+    async fn test_set_format() {
+        let (capture_tx, _rx) = mpsc::channel(1);
+        let context = TestContext::new(capture_tx);
+        let app = crate::service::route(Router::new()).with_state(context.clone());
+        let put = |body: &str| {
+            HttpRequest::put("/device/0/format")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let send = |req: HttpRequest<Body>| {
+            let app = app.clone();
+            async move {
+                let res = app.oneshot(req).await.unwrap();
+                let status = res.status();
+                let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                (status, body)
+            }
+        };
+
+        // ドライバが調整した大きさを返す
+        let (status, body) =
+            send(put(r#"{"fourcc": "RG12", "width": 1920, "height": 1080}"#)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!({"fourcc": "RG12", "width": 1936, "height": 1096})
+        );
+
+        // 対応していないfourccや不正なリクエストはデバイスに設定しない
+        let cases = [
+            (
+                r#"{"fourcc": "YUYV", "width": 1920, "height": 1080}"#,
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                r#"{"fourcc": "RG12", "width": 0, "height": 1080}"#,
+                StatusCode::BAD_REQUEST,
+            ),
+            (r#"{"fourcc": "RG12"}"#, StatusCode::BAD_REQUEST),
+        ];
+        for (body, expected) in cases {
+            let (status, problem) = send(put(body)).await;
+            assert_eq!(status, expected, "{}", problem);
+        }
+        let requested = context.format_device.requested.lock().unwrap();
+        assert_eq!(*requested, [(1920, 1080)]);
+    }
+
+    #[test]
+    fn test_format_raw_to_png_keeps_bayer_phase() {
+        let (width, height) = (16, 12);
+        // RGGBの色ごとに異なる値を持つ合成フレーム
+        let color = |x: u32, y: u32| {
+            [[0x1000_u16, 0x8000], [0x8000, 0xf000]][y as usize % 2][x as usize % 2]
+        };
+        let buffer = (0..height)
+            .flat_map(|y| (0..width).map(move |x| color(x, y)))
+            .flat_map(|v| v.to_ne_bytes())
+            .collect();
+        let format = CaptureFormat {
+            fourcc: "RG12".to_string(),
+            width,
+            height,
+        };
+        let mut res = CaptureResponse {
+            meta: CaptureMeta::new(0, &Metadata::default(), format.clone(), vec![]),
+            format,
+            buffer,
+        };
+        let roi = Roi {
+            x: 4,
+            y: 2,
+            width: 8,
+            height: 6,
+        };
+        check_raw_crop(Some(roi), None).unwrap();
+        format_raw_to_png(&mut res, Some(roi)).unwrap();
+        assert_eq!((res.format.width, res.format.height), (8, 6));
+
+        // 切り出し後もRの位置にはRの値が残る
+        let img = image::load_from_memory(&res.buffer).unwrap().into_luma16();
+        assert_eq!(img.dimensions(), (8, 6));
+        for (x, y, px) in img.enumerate_pixels() {
+            assert_eq!(px[0], color(x, y), "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn test_check_raw_crop() {
+        let roi = Roi {
+            x: 4,
+            y: 2,
+            width: 8,
+            height: 6,
+        };
+        assert!(check_raw_crop(None, None).is_ok());
+        assert!(check_raw_crop(Some(roi), None).is_ok());
+        // 奇数の原点や大きさは色の並びがずれる
+        for roi in [Roi { x: 3, ..roi }, Roi { width: 7, ..roi }] {
+            let err = check_raw_crop(Some(roi), None).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::BadRequest);
+        }
+        let err = check_raw_crop(Some(roi), Some(0.5)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BadRequest);
+    }
+
+    #[test]
+    fn test_check_crop() {
+        let roi = Roi {
+            x: 4,
+            y: 2,
+            width: 8,
+            height: 6,
+        };
+        let kind = |fourcc, width, roi, scale| {
+            check_crop(fourcc, width, 8, roi, scale)
+                .err()
+                .map(|e| e.kind())
+        };
+        assert_eq!(kind("YUYV", 16, Some(roi), Some(0.5)), None);
+        assert_eq!(kind("RG12", 16, Some(roi), None), None);
+        // 切り出せないfourccでは指定を無視しない
+        assert_eq!(kind("MJPG", 16, None, None), None);
+        assert_eq!(
+            kind("MJPG", 16, Some(roi), None),
+            Some(ErrorKind::UnsupportedFormat)
+        );
+        assert_eq!(
+            kind("GREY", 16, None, Some(0.5)),
+            Some(ErrorKind::UnsupportedFormat)
+        );
+        // フレームからはみ出す領域
+        assert_eq!(
+            kind("YUYV", 10, Some(roi), None),
+            Some(ErrorKind::BadRequest)
+        );
+        assert_eq!(
+            kind("RG10", 10, Some(roi), None),
+            Some(ErrorKind::BadRequest)
+        );
+        assert_eq!(
+            kind("RG10", 16, Some(roi), Some(0.5)),
+            Some(ErrorKind::BadRequest)
+        );
+    }
+
+    #[test]
+    fn test_capture_query() {
+        let q: CaptureQuery = query("fourcc=RG12&width=640&roi=2,4,8,6&scale=0.5").unwrap();
+        assert_eq!(q.common.fourcc.as_deref(), Some("RG12"));
+        assert_eq!((q.common.width, q.common.height), (Some(640), None));
+        assert_eq!(q.common.buffer_count, 4);
+        assert_eq!(q.common.outfmt, OutFmt::Default);
+        assert_eq!(q.roi.map(|r| (r.x, r.width)), Some((2, 8)));
+        assert_eq!(q.scale, Some(0.5));
+
+        let q: CaptureStackQuery = query("buffer_count=2&outfmt=png").unwrap();
+        assert_eq!(q.common.buffer_count, 2);
+        assert_eq!(q.common.outfmt, OutFmt::Png);
+        assert_eq!(q.stack_count, 5);
+
+        let q: CaptureBurstQuery = query("count=8&format=png&height=480").unwrap();
+        assert_eq!(q.count, 8);
+        assert_eq!(q.common.outfmt, OutFmt::Png);
+        assert_eq!(q.common.height, Some(480));
+        assert_eq!(query::<CaptureBurstQuery>("").unwrap().count, 3);
+
+        assert!(query::<CaptureBurstQuery>("count=abc").is_err());
+        assert!(query::<CaptureQuery>("width=-1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_capture_burst_count_cap() {
+        let (capture_tx, _rx) = mpsc::channel(1);
+        let app = crate::service::route(Router::new()).with_state(TestContext::new(capture_tx));
+        // デバイスを開く前に枚数の上限を確認する
+        for count in [0, 5] {
+            let req = HttpRequest::get(format!("/device/0/burst?count={}", count))
+                .body(Body::empty())
+                .unwrap();
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(
+                problem["detail"],
+                format!("count must be in 1..=4, got {}", count)
+            );
+        }
+    }
+
+    #[test]
+    fn test_burst_to_zip() {
+        let (width, height) = (8, 4);
+        let format = CaptureFormat {
+            fourcc: "RG12".to_string(),
+            width,
+            height,
+        };
+        let burst = |count: usize| CaptureBurstResponse {
+            meta: CaptureMeta::new(0, &Metadata::default(), format.clone(), vec![]),
+            format: format.clone(),
+            frames: (0..count)
+                .map(|i| vec![i as u8 * 16; (width * height * 2) as usize])
+                .collect(),
+        };
+
+        let zip = burst_to_zip(burst(3), &OutFmt::Default).unwrap();
+        let mut zip = zip::ZipArchive::new(Cursor::new(zip)).unwrap();
+        assert_eq!(zip.len(), 3);
+        for i in 0..3 {
+            let file = zip.by_index(i).unwrap();
+            assert_eq!(file.name(), format!("frame_{:03}.raw", i));
+            assert_eq!(file.size(), (width * height * 2) as u64);
+        }
+
+        let zip = burst_to_zip(burst(2), &OutFmt::Png).unwrap();
+        let mut zip = zip::ZipArchive::new(Cursor::new(zip)).unwrap();
+        assert_eq!(zip.len(), 2);
+        let mut png = vec![];
+        std::io::Read::read_to_end(&mut zip.by_name("frame_001.png").unwrap(), &mut png).unwrap();
+        let img = image::load_from_memory(&png).unwrap();
+        assert_eq!((img.width(), img.height()), (width, height));
+    }
+}
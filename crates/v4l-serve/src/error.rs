@@ -1,17 +1,127 @@
 use axum::{
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
+    Json,
 };
 
+/// エラーの種類
+///
+/// レスポンスのステータスコードとRFC 7807の`type`、`title`を決める
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// リクエストのパラメータが不正
+    BadRequest,
+    /// デバイスや要求したデータが存在しない
+    NotFound,
+    /// デバイスが他の操作で使用中
+    DeviceBusy,
+    /// 指定された画像フォーマットに対応していない
+    UnsupportedFormat,
+    /// その他のサーバー内部のエラー
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorKind::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::DeviceBusy => StatusCode::CONFLICT,
+            ErrorKind::UnsupportedFormat => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            ErrorKind::BadRequest => "Bad request",
+            ErrorKind::NotFound => "Not found",
+            ErrorKind::DeviceBusy => "Device busy",
+            ErrorKind::UnsupportedFormat => "Unsupported format",
+            ErrorKind::Internal => "Internal error",
+        }
+    }
+
+    /// 問題の種類を識別するURI
+    pub fn type_uri(&self) -> &'static str {
+        match self {
+            ErrorKind::BadRequest => "urn:v4l-serve:problem:bad-request",
+            ErrorKind::NotFound => "urn:v4l-serve:problem:not-found",
+            ErrorKind::DeviceBusy => "urn:v4l-serve:problem:device-busy",
+            ErrorKind::UnsupportedFormat => "urn:v4l-serve:problem:unsupported-format",
+            ErrorKind::Internal => "urn:v4l-serve:problem:internal",
+        }
+    }
+
+    // エラーの原因からエラーの種類を推定する
+    fn classify(err: &anyhow::Error) -> Self {
+        let io = err
+            .chain()
+            .filter_map(|e| e.downcast_ref::<std::io::Error>())
+            .next();
+        match io.and_then(|e| e.raw_os_error()) {
+            Some(libc::ENOENT | libc::ENODEV | libc::ENXIO) => ErrorKind::NotFound,
+            Some(libc::EBUSY) => ErrorKind::DeviceBusy,
+            _ => ErrorKind::Internal,
+        }
+    }
+}
+
+/// RFC 7807 `application/problem+json`のレスポンスボディ
+#[derive(Debug, serde::Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub typ: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    pub detail: String,
+}
+
 /// Application error type
 #[derive(Debug)]
-pub struct AppError(anyhow::Error);
+pub struct AppError {
+    kind: ErrorKind,
+    err: anyhow::Error,
+}
+
+impl AppError {
+    pub fn new(kind: ErrorKind, err: impl Into<anyhow::Error>) -> Self {
+        Self {
+            kind,
+            err: err.into(),
+        }
+    }
+
+    /// リクエストのパラメータが不正
+    pub fn bad_request(err: impl Into<anyhow::Error>) -> Self {
+        Self::new(ErrorKind::BadRequest, err)
+    }
+
+    /// 指定された画像フォーマットに対応していない
+    pub fn unsupported_format(err: impl Into<anyhow::Error>) -> Self {
+        Self::new(ErrorKind::UnsupportedFormat, err)
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn problem(&self) -> Problem {
+        Problem {
+            typ: self.kind.type_uri(),
+            title: self.kind.title(),
+            status: self.kind.status().as_u16(),
+            detail: self.err.to_string(),
+        }
+    }
+}
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Internal Error: {}", self.0),
+            self.kind.status(),
+            [(header::CONTENT_TYPE, "application/problem+json")],
+            Json(self.problem()),
         )
             .into_response()
     }
@@ -22,6 +132,61 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        let err = err.into();
+        Self {
+            kind: ErrorKind::classify(&err),
+            err,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::header, response::IntoResponse};
+
+    use super::{AppError, ErrorKind};
+
+    async fn problem_json(err: AppError) -> (u16, String, serde_json::Value) {
+        let res = err.into_response();
+        let status = res.status().as_u16();
+        let content_type = res.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, content_type, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_problem_json() {
+        let (status, content_type, body) =
+            problem_json(AppError::bad_request(anyhow::anyhow!("invalid roi"))).await;
+        assert_eq!(status, 400);
+        assert_eq!(content_type, "application/problem+json");
+        assert_eq!(body["type"], "urn:v4l-serve:problem:bad-request");
+        assert_eq!(body["title"], "Bad request");
+        assert_eq!(body["status"], 400);
+        assert_eq!(body["detail"], "invalid roi");
+
+        let (status, _, body) =
+            problem_json(AppError::unsupported_format(anyhow::anyhow!("YU12"))).await;
+        assert_eq!(status, 422);
+        assert_eq!(body["type"], "urn:v4l-serve:problem:unsupported-format");
+
+        let (status, _, body) = problem_json(anyhow::anyhow!("unexpected").into()).await;
+        assert_eq!(status, 500);
+        assert_eq!(body["status"], 500);
+    }
+
+    #[test]
+    fn test_classify_io_error() {
+        let err = AppError::from(std::io::Error::from_raw_os_error(libc::ENOENT));
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = AppError::from(std::io::Error::from_raw_os_error(libc::EBUSY));
+        assert_eq!(err.kind(), ErrorKind::DeviceBusy);
+        let err = AppError::from(std::io::Error::from_raw_os_error(libc::EINVAL));
+        assert_eq!(err.kind(), ErrorKind::Internal);
     }
 }
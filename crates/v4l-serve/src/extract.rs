@@ -0,0 +1,211 @@
+//! リクエストの抽出
+//!
+//! axumの抽出器はパースに失敗すると`text/plain`のレスポンスを返すため、
+//! 他のエラーと同じ`application/problem+json`の`400 Bad Request`になるようにラップする
+
+use axum::{
+    async_trait,
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        FromRequest, FromRequestParts, Request,
+    },
+    http::request::Parts,
+    response::{IntoResponse, Response},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::AppError;
+
+/// クエリ文字列をデシリアライズする
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        axum::extract::Query::<T>::from_request_parts(parts, state)
+            .await
+            .map(|q| Query(q.0))
+            .map_err(|e: QueryRejection| rejection(e.body_text()))
+    }
+}
+
+/// パスのパラメータをデシリアライズする
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Path<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        axum::extract::Path::<T>::from_request_parts(parts, state)
+            .await
+            .map(|p| Path(p.0))
+            .map_err(|e: PathRejection| rejection(e.body_text()))
+    }
+}
+
+/// JSONのリクエストボディをデシリアライズする
+///
+/// レスポンスとしては`axum::Json`と同じく`application/json`でシリアライズする
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        axum::Json::<T>::from_request(req, state)
+            .await
+            .map(|j| Json(j.0))
+            .map_err(|e: JsonRejection| rejection(e.body_text()))
+    }
+}
+
+impl<T> IntoResponse for Json<T>
+where
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+/// multipartのリクエストボディ
+#[cfg(feature = "lut")]
+#[derive(Debug)]
+pub struct Multipart(pub axum::extract::Multipart);
+
+#[cfg(feature = "lut")]
+#[async_trait]
+impl<S> FromRequest<S> for Multipart
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        axum::extract::Multipart::from_request(req, state)
+            .await
+            .map(Multipart)
+            .map_err(|e: axum::extract::multipart::MultipartRejection| rejection(e.body_text()))
+    }
+}
+
+// 抽出に失敗した理由をそのまま`detail`にする
+fn rejection(text: String) -> AppError {
+    tracing::warn!("Rejected request: {}", text);
+    AppError::bad_request(anyhow::anyhow!(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+        routing::{get, put},
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use super::{Json, Path, Query};
+
+    #[derive(Debug, serde::Deserialize, serde::Serialize)]
+    struct Size {
+        width: u32,
+        height: u32,
+    }
+
+    fn app() -> Router {
+        Router::new()
+            .route(
+                "/device/:index",
+                get(
+                    |Path(index): Path<usize>, Query(size): Query<Size>| async move {
+                        format!("{} {}x{}", index, size.width, size.height)
+                    },
+                ),
+            )
+            .route(
+                "/size",
+                put(|Json(size): Json<Size>| async move { Json(size) }),
+            )
+    }
+
+    async fn send(req: Request<Body>) -> (StatusCode, Option<String>, Vec<u8>) {
+        let res = app().oneshot(req).await.unwrap();
+        let status = res.status();
+        let content_type = res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|v| v.to_str().unwrap().to_string());
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, content_type, body.to_vec())
+    }
+
+    fn get_req(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    fn put_json(body: &str) -> Request<Body> {
+        Request::put("/size")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_extract_ok() {
+        let (status, _, body) = send(get_req("/device/1?width=640&height=480")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, b"1 640x480");
+
+        let (status, content_type, body) = send(put_json(r#"{"width": 8, "height": 4}"#)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type.as_deref(), Some("application/json"));
+        let size: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(size, serde_json::json!({"width": 8, "height": 4}));
+    }
+
+    #[tokio::test]
+    async fn test_extract_rejection() {
+        let json_without_content_type = Request::put("/size")
+            .body(Body::from(r#"{"width": 8, "height": 4}"#))
+            .unwrap();
+        let cases = [
+            get_req("/device/abc?width=640&height=480"),
+            get_req("/device/1?width=wide&height=480"),
+            get_req("/device/1?width=640"),
+            put_json(r#"{"width": 8"#),
+            put_json(r#"{"width": -8, "height": 4}"#),
+            json_without_content_type,
+        ];
+        for req in cases {
+            let uri = req.uri().clone();
+            let (status, content_type, body) = send(req).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", uri);
+            assert_eq!(content_type.as_deref(), Some("application/problem+json"));
+            let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(problem["type"], "urn:v4l-serve:problem:bad-request");
+            assert!(!problem["detail"].as_str().unwrap().is_empty());
+        }
+    }
+}
@@ -1,5 +1,10 @@
+use std::io::{Cursor, Write};
+
+use image::{imageops::FilterType, ImageBuffer, Pixel};
 use yuvutils_rs::{YuvPackedImage, YuvRange, YuvStandardMatrix};
 
+use crate::capture::Roi;
+
 pub fn yuyv422_to_rgb(buf: &[u8], width: u32, height: u32) -> anyhow::Result<Vec<u8>> {
     let src = YuvPackedImage {
         width,
@@ -14,3 +19,90 @@ pub fn yuyv422_to_rgb(buf: &[u8], width: u32, height: u32) -> anyhow::Result<Vec
     yuvutils_rs::yuyv422_to_rgb(&src, &mut rgb, rgb_stride, range, matrix)?;
     Ok(rgb)
 }
+
+/// 画像から領域を切り出して縮小する
+pub fn crop_scale<P>(
+    img: ImageBuffer<P, Vec<P::Subpixel>>,
+    roi: Option<Roi>,
+    scale: Option<f32>,
+) -> anyhow::Result<ImageBuffer<P, Vec<P::Subpixel>>>
+where
+    P: Pixel + 'static,
+{
+    let img = match roi {
+        Some(roi) => {
+            roi.validate(img.width(), img.height())?;
+            image::imageops::crop_imm(&img, roi.x, roi.y, roi.width, roi.height).to_image()
+        }
+        None => img,
+    };
+    let img = match scale {
+        Some(scale) => {
+            let width = ((img.width() as f32 * scale).round() as u32).max(1);
+            let height = ((img.height() as f32 * scale).round() as u32).max(1);
+            image::imageops::resize(&img, width, height, FilterType::Triangle)
+        }
+        None => img,
+    };
+    Ok(img)
+}
+
+/// ファイル名とデータの組をZIPにまとめる
+///
+/// 画像は圧縮済みのものが多いため無圧縮で格納する
+pub fn zip_files(files: Vec<(String, Vec<u8>)>) -> anyhow::Result<Vec<u8>> {
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let options =
+        zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    for (name, data) in files {
+        zip.start_file(name, options)?;
+        zip.write_all(&data)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Luma, Rgb};
+
+    use super::*;
+
+    #[test]
+    fn test_crop_scale() {
+        let img = image::RgbImage::from_fn(64, 48, |x, y| Rgb([x as u8, y as u8, 0]));
+        let roi = Roi {
+            x: 16,
+            y: 12,
+            width: 32,
+            height: 24,
+        };
+        let out = crop_scale(img, Some(roi), Some(0.5)).unwrap();
+        assert_eq!(out.dimensions(), (16, 12));
+        // 左上は切り出し領域の原点付近の値になる
+        assert!(out.get_pixel(0, 0)[0] >= 16);
+
+        let img = ImageBuffer::<Luma<u16>, _>::new(64, 48);
+        let out = crop_scale(img.clone(), None, None).unwrap();
+        assert_eq!(out.dimensions(), (64, 48));
+        let roi = Roi { x: 40, ..roi };
+        assert!(crop_scale(img, Some(roi), None).is_err());
+    }
+
+    #[test]
+    fn test_zip_files() {
+        let files = (0..3)
+            .map(|i| (format!("frame_{:03}.raw", i), vec![i as u8; 16]))
+            .collect();
+        let buf = zip_files(files).unwrap();
+
+        let mut zip = zip::ZipArchive::new(Cursor::new(buf)).unwrap();
+        assert_eq!(zip.len(), 3);
+        for i in 0..3 {
+            let mut file = zip.by_index(i).unwrap();
+            assert_eq!(file.name(), format!("frame_{:03}.raw", i));
+            let mut data = vec![];
+            std::io::Read::read_to_end(&mut file, &mut data).unwrap();
+            assert_eq!(data, vec![i as u8; 16]);
+        }
+    }
+}
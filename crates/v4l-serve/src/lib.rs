@@ -2,6 +2,10 @@ pub mod capture;
 pub mod context;
 pub mod device;
 pub mod error;
+pub mod extract;
 pub(crate) mod imgfmt;
+pub mod lock;
+#[cfg(feature = "lut")]
+pub mod lut;
 pub mod service;
 pub(crate) mod util;
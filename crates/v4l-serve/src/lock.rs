@@ -0,0 +1,151 @@
+//! デバイスごとの排他制御
+//!
+//! 同じデバイスに対する操作が並行するとV4L2の状態が壊れるため、リクエスト単位でロックを取る。
+//! 読み取りのみの操作は共有ロック、フォーマットの設定や撮影は排他ロックを使う。
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+use crate::error::{AppError, ErrorKind};
+
+/// ロック取得を待つ時間の既定値
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// デバイスのindexごとのロック
+#[derive(Debug, Clone)]
+pub struct DeviceLocks {
+    locks: Arc<Mutex<HashMap<usize, Arc<RwLock<()>>>>>,
+    timeout: Duration,
+}
+
+impl Default for DeviceLocks {
+    fn default() -> Self {
+        Self::new(DEFAULT_LOCK_TIMEOUT)
+    }
+}
+
+impl DeviceLocks {
+    /// `timeout`以内にロックを取得できない場合は`409 Conflict`とする
+    pub fn new(timeout: Duration) -> Self {
+        DeviceLocks {
+            locks: Arc::new(Mutex::new(HashMap::new())),
+            timeout,
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// 読み取り用の共有ロックを取得する
+    pub async fn read(&self, index: usize) -> Result<OwnedRwLockReadGuard<()>, AppError> {
+        tokio::time::timeout(self.timeout, self.get(index).read_owned())
+            .await
+            .map_err(|_| busy(index))
+    }
+
+    /// 書き込み用の排他ロックを取得する
+    pub async fn write(&self, index: usize) -> Result<OwnedRwLockWriteGuard<()>, AppError> {
+        tokio::time::timeout(self.timeout, self.get(index).write_owned())
+            .await
+            .map_err(|_| busy(index))
+    }
+
+    // デバイスのロックを取得する。未登録の場合は作成する
+    //
+    // ガードや待機中のリクエストから参照されていないロックはここで破棄するため、
+    // 存在しないindexへのリクエストが続いても登録数は同時に使われているデバイスの数に収まる
+    fn get(&self, index: usize) -> Arc<RwLock<()>> {
+        let mut locks = self.locks.lock().unwrap();
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        locks.entry(index).or_default().clone()
+    }
+}
+
+// ロックの取得がタイムアウトした場合のエラー
+fn busy(index: usize) -> AppError {
+    tracing::warn!("Device {} is busy", index);
+    AppError::new(
+        ErrorKind::DeviceBusy,
+        anyhow::anyhow!("Device {} is busy", index),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::StatusCode;
+
+    use super::DeviceLocks;
+    use crate::error::ErrorKind;
+
+    // フォーマット設定を模した排他ロック内の処理
+    async fn set_format(
+        locks: &DeviceLocks,
+        index: usize,
+        work: Duration,
+    ) -> Result<(), StatusCode> {
+        let _guard = locks.write(index).await.map_err(|e| e.kind().status())?;
+        tokio::time::sleep(work).await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_concurrent_write_conflict() {
+        let locks = DeviceLocks::new(Duration::from_millis(100));
+        let (a, b) = tokio::join!(
+            set_format(&locks, 0, Duration::from_millis(500)),
+            set_format(&locks, 0, Duration::from_millis(500)),
+        );
+        let mut res = [a, b];
+        res.sort();
+        assert_eq!(res, [Ok(()), Err(StatusCode::CONFLICT)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_concurrent_write_serialize() {
+        let locks = DeviceLocks::new(Duration::from_secs(1));
+        let start = tokio::time::Instant::now();
+        let (a, b) = tokio::join!(
+            set_format(&locks, 0, Duration::from_millis(300)),
+            set_format(&locks, 0, Duration::from_millis(300)),
+        );
+        assert_eq!((a, b), (Ok(()), Ok(())));
+        // 2つ目は1つ目の完了を待ってから実行される
+        assert!(start.elapsed() >= Duration::from_millis(600));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_shared() {
+        let locks = DeviceLocks::new(Duration::from_millis(100));
+        let _r1 = locks.read(0).await.unwrap();
+        let _r2 = locks.read(0).await.unwrap();
+        let err = locks.write(0).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DeviceBusy);
+        // 別のデバイスは影響を受けない
+        assert!(locks.write(1).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unused_locks_are_dropped() {
+        let locks = DeviceLocks::new(Duration::from_millis(100));
+        let held = locks.write(0).await.unwrap();
+        // 存在しないデバイスへのリクエストが続いても増え続けない
+        for index in 1..100 {
+            let _guard = locks.read(index).await.unwrap();
+        }
+        assert!(locks.locks.lock().unwrap().len() <= 2);
+
+        // 使用中のロックは破棄されず、排他が保たれる
+        let err = locks.write(0).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DeviceBusy);
+        drop(held);
+        assert!(locks.write(0).await.is_ok());
+    }
+}
@@ -0,0 +1,337 @@
+//! LUT/レジスタテーブルのアップロード
+//!
+//! カメラ固有のキャリブレーションテーブルをmultipartで受け取り、デバイスのコントロールとして適用する
+
+use axum::{
+    extract::{multipart::MultipartError, State},
+    response::IntoResponse,
+};
+use v4l::{control::Value, Control};
+
+use crate::{
+    context::Context,
+    error::AppError,
+    extract::{Json, Multipart, Path},
+};
+
+/// 1レコードのバイト数(control id: u32 LE + value: i32 LE)
+pub const RECORD_SIZE: usize = 8;
+
+/// 1回のアップロードで受け付ける最大レコード数
+pub const MAX_RECORDS: usize = 1024;
+
+/// multipartでLUTを受け取るフィールド名
+const FIELD_NAME: &str = "lut";
+
+/// レジスタテーブル
+///
+/// `(control id, value)`の組をリトルエンディアンで連続して並べたバイナリ
+#[derive(Debug, PartialEq)]
+pub struct RegisterTable(pub Vec<(u32, i32)>);
+
+impl RegisterTable {
+    /// バイナリを検証してテーブルに変換する
+    pub fn parse(buf: &[u8]) -> anyhow::Result<Self> {
+        if buf.is_empty() {
+            anyhow::bail!("LUT is empty");
+        }
+        if buf.len() % RECORD_SIZE != 0 {
+            anyhow::bail!(
+                "LUT size must be a multiple of {} bytes, got {}",
+                RECORD_SIZE,
+                buf.len()
+            );
+        }
+        let count = buf.len() / RECORD_SIZE;
+        if count > MAX_RECORDS {
+            anyhow::bail!("Too many LUT records: {} > {}", count, MAX_RECORDS);
+        }
+        let records = buf
+            .chunks_exact(RECORD_SIZE)
+            .map(|r| {
+                let id = u32::from_le_bytes([r[0], r[1], r[2], r[3]]);
+                let value = i32::from_le_bytes([r[4], r[5], r[6], r[7]]);
+                (id, value)
+            })
+            .collect();
+        Ok(RegisterTable(records))
+    }
+
+    /// v4lのコントロールに変換する
+    pub fn to_controls(&self) -> Vec<Control> {
+        self.0
+            .iter()
+            .map(|(id, value)| Control {
+                id: *id,
+                value: Value::Integer(*value as i64),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct UploadResult {
+    pub applied: usize,
+}
+
+/// コントロールの一覧の取得と設定ができるデバイス
+pub trait ControlDevice {
+    /// デバイスが持つコントロールのid
+    fn control_ids(&self) -> std::io::Result<Vec<u32>>;
+
+    fn set_controls(&self, controls: Vec<Control>) -> std::io::Result<()>;
+}
+
+impl ControlDevice for v4l::Device {
+    fn control_ids(&self) -> std::io::Result<Vec<u32>> {
+        Ok(self.query_controls()?.into_iter().map(|c| c.id).collect())
+    }
+
+    fn set_controls(&self, controls: Vec<Control>) -> std::io::Result<()> {
+        v4l::Device::set_controls(self, controls)
+    }
+}
+
+/// LUTをアップロードしてデバイスに適用する
+pub async fn upload<C>(
+    State(context): State<C>,
+    Path(index): Path<usize>,
+    Multipart(multipart): Multipart,
+) -> Result<impl IntoResponse, AppError>
+where
+    C: Context,
+{
+    let table = read_table(multipart).await?;
+    let _guard = context.device_locks().write(index).await?;
+    let dev = context.open_control_device(index)?;
+    Ok(Json(apply(dev.as_ref(), &table)?))
+}
+
+// multipartの`lut`フィールドを読み出してテーブルに変換する
+async fn read_table(mut multipart: axum::extract::Multipart) -> Result<RegisterTable, AppError> {
+    let mut blob = None;
+    while let Some(field) = multipart.next_field().await.map_err(invalid_multipart)? {
+        if field.name() == Some(FIELD_NAME) {
+            blob = Some(field.bytes().await.map_err(invalid_multipart)?);
+            break;
+        }
+    }
+    let Some(blob) = blob else {
+        return Err(AppError::bad_request(anyhow::anyhow!(
+            "Missing multipart field: {}",
+            FIELD_NAME
+        )));
+    };
+    RegisterTable::parse(&blob).map_err(|e| {
+        tracing::warn!("Invalid LUT: {:?}", e);
+        AppError::bad_request(e)
+    })
+}
+
+// デバイスが持たないコントロールが含まれていないことを確認してから適用する
+fn apply<D: ControlDevice + ?Sized>(
+    dev: &D,
+    table: &RegisterTable,
+) -> Result<UploadResult, AppError> {
+    let known = dev.control_ids()?;
+    let unknown: Vec<String> = table
+        .0
+        .iter()
+        .filter(|(id, _)| !known.contains(id))
+        .map(|(id, _)| format!("{:#010x}", id))
+        .collect();
+    if !unknown.is_empty() {
+        tracing::warn!("Unknown control ids in LUT: {:?}", unknown);
+        return Err(AppError::bad_request(anyhow::anyhow!(
+            "Unknown control ids: {}",
+            unknown.join(",")
+        )));
+    }
+    dev.set_controls(table.to_controls()).inspect_err(|e| {
+        tracing::error!("Failed to apply LUT: {:?}", e);
+    })?;
+    Ok(UploadResult {
+        applied: table.0.len(),
+    })
+}
+
+// multipartの読み出しに失敗した場合は不正なリクエストとする
+fn invalid_multipart(err: MultipartError) -> AppError {
+    tracing::warn!("Invalid multipart: {:?}", err);
+    AppError::bad_request(anyhow::anyhow!("Invalid multipart: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+        Router,
+    };
+    use tokio::sync::mpsc;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{context, lock::DeviceLocks};
+
+    const BOUNDARY: &str = "lut-boundary";
+
+    // 指定したコントロールのみを持つデバイス
+    #[derive(Clone)]
+    struct MockDevice {
+        ids: Vec<u32>,
+        applied: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl ControlDevice for MockDevice {
+        fn control_ids(&self) -> std::io::Result<Vec<u32>> {
+            Ok(self.ids.clone())
+        }
+
+        fn set_controls(&self, controls: Vec<Control>) -> std::io::Result<()> {
+            let mut applied = self.applied.lock().unwrap();
+            applied.extend(controls.iter().map(|c| c.id));
+            Ok(())
+        }
+    }
+
+    // デバイスの代わりにモックを開くコンテキスト
+    #[derive(Clone)]
+    struct TestContext {
+        capture_tx: mpsc::Sender<context::Request>,
+        device_locks: DeviceLocks,
+        dev: MockDevice,
+    }
+
+    impl Context for TestContext {
+        fn capture_tx(&self) -> mpsc::Sender<context::Request> {
+            self.capture_tx.clone()
+        }
+
+        fn device_locks(&self) -> &DeviceLocks {
+            &self.device_locks
+        }
+
+        fn open_control_device(&self, _index: usize) -> anyhow::Result<Box<dyn ControlDevice>> {
+            Ok(Box::new(self.dev.clone()))
+        }
+    }
+
+    fn records(records: &[(u32, i32)]) -> Vec<u8> {
+        let mut buf = vec![];
+        for (id, value) in records {
+            buf.extend_from_slice(&id.to_le_bytes());
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        buf
+    }
+
+    fn multipart_body(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut body = format!(
+            "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"; filename=\"lut.bin\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n"
+        )
+        .into_bytes();
+        body.extend_from_slice(data);
+        body.extend_from_slice(format!("\r\n--{BOUNDARY}--\r\n").as_bytes());
+        body
+    }
+
+    fn post_lut(content_type: &str, body: Vec<u8>) -> Request<Body> {
+        Request::post("/device/0/lut")
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    fn post_multipart(body: Vec<u8>) -> Request<Body> {
+        post_lut(&format!("multipart/form-data; boundary={BOUNDARY}"), body)
+    }
+
+    // `service::route`のLUTアップロードにリクエストを送る
+    async fn send(dev: MockDevice, req: Request<Body>) -> (StatusCode, serde_json::Value) {
+        let (capture_tx, _rx) = mpsc::channel(1);
+        let app = crate::service::route(Router::new()).with_state(TestContext {
+            capture_tx,
+            device_locks: DeviceLocks::default(),
+            dev,
+        });
+        let res = app.oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn mock_device() -> MockDevice {
+        MockDevice {
+            ids: vec![0x009a_0901, 0x009a_0902],
+            applied: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upload_valid() {
+        let dev = mock_device();
+        let blob = records(&[(0x009a_0901, 120), (0x009a_0902, -1)]);
+        let (status, body) = send(
+            dev.clone(),
+            post_multipart(multipart_body(FIELD_NAME, &blob)),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["applied"], 2);
+        assert_eq!(*dev.applied.lock().unwrap(), [0x009a_0901, 0x009a_0902]);
+    }
+
+    #[tokio::test]
+    async fn test_upload_invalid() {
+        let valid = records(&[(0x009a_0901, 120)]);
+        let cases = [
+            // サイズがレコードの倍数でない
+            post_multipart(multipart_body(FIELD_NAME, &valid[..RECORD_SIZE - 1])),
+            // `lut`フィールドがない
+            post_multipart(multipart_body("table", &valid)),
+            // デバイスが持たないコントロール
+            post_multipart(multipart_body(FIELD_NAME, &records(&[(0x0098_0913, 1)]))),
+            // 終端のないmultipart
+            post_multipart(multipart_body(FIELD_NAME, &valid)[..40].to_vec()),
+            // multipartではないリクエスト
+            post_lut("application/octet-stream", valid.clone()),
+            post_lut("multipart/form-data", multipart_body(FIELD_NAME, &valid)),
+        ];
+        for req in cases {
+            let dev = mock_device();
+            let (status, problem) = send(dev.clone(), req).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", problem);
+            assert_eq!(problem["type"], "urn:v4l-serve:problem:bad-request");
+            assert!(dev.applied.lock().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_parse_register_table() {
+        let mut buf = vec![];
+        buf.extend_from_slice(&0x009a_0901_u32.to_le_bytes());
+        buf.extend_from_slice(&120_i32.to_le_bytes());
+        buf.extend_from_slice(&0x009a_0902_u32.to_le_bytes());
+        buf.extend_from_slice(&(-1_i32).to_le_bytes());
+
+        let table = RegisterTable::parse(&buf).unwrap();
+        assert_eq!(
+            table,
+            RegisterTable(vec![(0x009a_0901, 120), (0x009a_0902, -1)])
+        );
+        assert_eq!(table.to_controls().len(), 2);
+    }
+
+    #[test]
+    fn test_parse_register_table_invalid() {
+        assert!(RegisterTable::parse(&[]).is_err());
+        assert!(RegisterTable::parse(&[0; RECORD_SIZE + 3]).is_err());
+        assert!(RegisterTable::parse(&vec![0; RECORD_SIZE * (MAX_RECORDS + 1)]).is_err());
+    }
+}
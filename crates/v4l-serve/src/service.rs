@@ -1,3 +1,5 @@
+#[cfg(feature = "lut")]
+use axum::routing::post;
 use axum::{routing::get, Router};
 
 use crate::{context::Context, device};
@@ -7,9 +9,13 @@ pub fn route<C>(router: Router<C>) -> Router<C>
 where
     C: Context + Clone + Send + Sync + 'static,
 {
-    router
+    let router = router
         .route("/devices", get(device::list))
-        .route("/device/:index", get(device::device))
+        .route("/device/:index", get(device::device::<C>))
+        .route(
+            "/device/:index/format",
+            get(device::get_format::<C>).put(device::set_format::<C>),
+        )
         .route("/device/:index/capture", get(device::capture::<C>))
         .route(
             "/device/:index/capture/avg",
@@ -19,4 +25,9 @@ where
             "/device/:index/capture/std",
             get(device::capture_stack_std::<C>),
         )
+        .route("/device/:index/burst", get(device::capture_burst::<C>))
+        .route("/device/:index/last-meta", get(device::last_meta::<C>));
+    #[cfg(feature = "lut")]
+    let router = router.route("/device/:index/lut", post(crate::lut::upload::<C>));
+    router
 }
@@ -1,4 +1,4 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
 use axum::Router;
 
@@ -13,6 +13,21 @@ struct Opt {
     addr: String,
     #[arg(short, long, default_value = "8080")]
     port: u16,
+    /// ドライバに要求するストリームバッファ数
+    #[arg(long, default_value = "4")]
+    stream_buffers: u32,
+    /// ストリームのI/O方式(mmap, userptr)。userptrに対応していない場合はmmapを使う
+    #[arg(long, default_value = "userptr")]
+    io_method: v4l_serve::capture::IoMethod,
+    /// デバイスが失われた場合に再接続を試みる間隔(msec)
+    #[arg(long, default_value = "1000")]
+    reconnect_interval_ms: u64,
+    /// 再接続を諦めるまでの時間(msec)
+    #[arg(long, default_value = "30000")]
+    reconnect_timeout_ms: u64,
+    /// デバイスが使用中の場合にロックを待つ時間(msec)。超えた場合は409を返す
+    #[arg(long, default_value = "5000")]
+    lock_timeout_ms: u64,
 }
 
 impl Opt {
@@ -25,12 +40,17 @@ impl Opt {
 #[derive(Clone)]
 struct Context {
     capture_tx: mpsc::Sender<v4l_serve::context::Request>,
+    device_locks: v4l_serve::lock::DeviceLocks,
 }
 
 impl v4l_serve::context::Context for Context {
     fn capture_tx(&self) -> mpsc::Sender<v4l_serve::context::Request> {
         self.capture_tx.clone()
     }
+
+    fn device_locks(&self) -> &v4l_serve::lock::DeviceLocks {
+        &self.device_locks
+    }
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -44,12 +64,24 @@ async fn main() -> anyhow::Result<()> {
 
     let opt = <Opt as clap::Parser>::parse();
 
-    let (mut cap_handle, capture_tx) = v4l_serve::capture::CaptureRoutine::new();
+    let config = v4l_serve::capture::CaptureConfig {
+        stream_buffers: opt.stream_buffers,
+        io_method: opt.io_method,
+        reconnect_interval: Duration::from_millis(opt.reconnect_interval_ms),
+        reconnect_timeout: Duration::from_millis(opt.reconnect_timeout_ms),
+    };
+    config.validate()?;
+    let (mut cap_handle, capture_tx) = v4l_serve::capture::CaptureRoutine::with_config(config);
+    let device_locks =
+        v4l_serve::lock::DeviceLocks::new(Duration::from_millis(opt.lock_timeout_ms));
     let token = CancellationToken::new();
 
     let router = v4l_serve::service::route(Router::new())
         .layer(TraceLayer::new_for_http())
-        .with_state(Context { capture_tx });
+        .with_state(Context {
+            capture_tx,
+            device_locks,
+        });
 
     let listener = tokio::net::TcpListener::bind(opt.addr()?).await?;
     tracing::info!("listening on {}", listener.local_addr()?);